@@ -0,0 +1,137 @@
+//! Records a session with a real sftp-server and writes what the server sent into the fuzz
+//! corpus: the whole byte stream for `decode_stream`, and the body of every response for the
+//! target that decodes its type.
+//!
+//!     cargo run --example record_fuzz_corpus -- /usr/lib/openssh/sftp-server fuzz/corpus
+
+extern crate sftp;
+extern crate rustc_serialize;
+extern crate tempdir;
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use rustc_serialize::hex::FromHex;
+use rustc_serialize::json::Json;
+use sftp::protocol::*;
+
+#[derive(Clone)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Exercises most of what a client sends, including requests that fail.
+fn session<W>(client: &mut sftp::Client<W>, dir: &str) where W: 'static + io::Write + Send {
+    let contents : Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+    let file = format!("{}/file", dir);
+    let _ = client.realpath(".");
+    let _ = client.create_dir_all(&format!("{}/a/b", dir)[..], 0o755);
+    let _ = client.write(&file[..], &contents);
+    let _ = client.append(&file[..], b"more");
+    let _ = client.read(&file[..]);
+    let _ = client.stat(&file[..]);
+    let _ = client.lstat(&file[..]);
+    let _ = client.set_permissions(&file[..], sftp::Permissions::from_mode(0o600));
+    let _ = client.list_dir(dir, &sftp::ListOptions::new());
+    let _ = client.copy(&file[..], &format!("{}/copy", dir)[..]);
+    let _ = client.write_atomic(&file[..], b"replaced");
+    let _ = client.checksum(&file[..], sftp::HashAlgorithm::Md5, None);
+    let _ = client.rename(&file[..], &format!("{}/renamed", dir)[..]);
+    let _ = client.readlink(&file[..]);
+    let _ = client.stat(&format!("{}/missing", dir)[..]);
+    let _ = client.rmdir(dir);
+    let _ = client.remove_dir_all(dir);
+}
+
+fn hex_field(obj: &Json, name: &str) -> Option<Vec<u8>> {
+    obj.find(name).and_then(|v| v.as_string()).and_then(|s| s.from_hex().ok())
+}
+
+// The response types with a fuzz target of their own, and where their body starts in the frame.
+fn target(msg_type: u8) -> Option<(&'static str, usize)> {
+    match msg_type {
+        SSH_FXP_VERSION => Some(("decode_version", 5)),
+        SSH_FXP_STATUS => Some(("decode_status", 9)),
+        SSH_FXP_HANDLE => Some(("decode_handle", 9)),
+        SSH_FXP_DATA => Some(("decode_data", 9)),
+        SSH_FXP_NAME => Some(("decode_name", 9)),
+        SSH_FXP_ATTRS => Some(("decode_file_attr", 9)),
+        _ => None,
+    }
+}
+
+fn save(corpus: &Path, target: &str, name: &str, data: &[u8]) {
+    let dir = corpus.join(target);
+    fs::create_dir_all(&dir).unwrap();
+    fs::File::create(dir.join(name)).unwrap().write_all(data).unwrap();
+}
+
+fn main() {
+    let args : Vec<String> = env::args().collect();
+    if args.len() != 3 {
+        println!("usage: {} SFTP_SERVER CORPUS_DIR", args[0]);
+        process::exit(2);
+    }
+    let corpus = Path::new(&args[2]);
+
+    let mut server = process::Command::new(&args[1])
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let trace = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+    let tracer = sftp::Tracer::new(trace.clone(), sftp::TraceFormat::JsonLines);
+    {
+        let mut client = sftp::Client::new(tracer.reader(server.stdout.take().unwrap()),
+                                           tracer.writer(server.stdin.take().unwrap())).unwrap();
+        let dir = tempdir::TempDir::new("sftp_corpus").unwrap();
+        session(&mut client, dir.path().to_str().unwrap());
+    }
+    let _ = server.wait();
+
+    let trace = String::from_utf8(trace.0.lock().unwrap().clone()).unwrap();
+    let mut stream = Vec::new();
+    let mut limits_ids = HashSet::new();
+    let mut counts : HashMap<&'static str, usize> = HashMap::new();
+    let mut seen = HashSet::new();
+    for line in trace.lines() {
+        let obj = Json::from_str(line).unwrap();
+        let frame = hex_field(&obj, "raw").unwrap();
+        let req_id = obj.find("req_id").and_then(|v| v.as_u64());
+        if obj.find("dir").and_then(|v| v.as_string()) == Some("send") {
+            // limits@openssh.com is the one extended reply with a decoder of its own.
+            if obj.find("packet").and_then(|v| v.as_string()).map_or(false, |p| p.starts_with("Limits")) {
+                limits_ids.extend(req_id);
+            }
+            continue;
+        }
+        stream.extend_from_slice(&frame);
+        let target = match frame[4] {
+            SSH_FXP_EXTENDED_REPLY if req_id.map_or(false, |id| limits_ids.contains(&id)) => Some(("decode_limits", 9)),
+            msg_type => target(msg_type),
+        };
+        if let Some((target, start)) = target {
+            if frame.len() >= start && seen.insert((target, frame[start..].to_vec())) {
+                let n = counts.entry(target).or_insert(0);
+                save(corpus, target, &format!("openssh-{}", n), &frame[start..]);
+                *n += 1;
+            }
+        }
+    }
+    save(corpus, "decode_stream", "openssh-session", &stream);
+    println!("recorded {} bytes from the server", stream.len());
+}
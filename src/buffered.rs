@@ -0,0 +1,172 @@
+use std::cmp;
+use std::io;
+use std::io::{BufRead, Read, Seek, Write};
+
+use error::Result;
+use File;
+
+/// Adds buffering to a `File`, so small reads and writes do not each cost a round trip. By
+/// default the buffer holds as much as the server allows in one read.
+///
+/// The position is tracked here, independently of the inner file's cursor. Buffered writes are
+/// flushed when the position moves, when reading, and on drop. Errors during drop are lost, so
+/// call `close` or `flush` to find out whether everything was written.
+pub struct BufFile<W> where W : 'static + io::Write + Send {
+    file: Option<File<W>>,
+    pos: u64,
+    cap: usize,
+    // Data at `pos - rpos..`, of which the first `rpos` bytes were consumed.
+    rbuf: Vec<u8>,
+    rpos: usize,
+    // Data to be written at `pos - wbuf.len()`.
+    wbuf: Vec<u8>,
+}
+
+impl<W> BufFile<W> where W : 'static + io::Write + Send {
+    pub fn new(file: File<W>) -> BufFile<W> {
+        let cap = file.client.max_read_len();
+        BufFile::with_capacity(cap, file)
+    }
+
+    pub fn with_capacity(cap: usize, file: File<W>) -> BufFile<W> {
+        let cap = cmp::max(cap, 1);
+        BufFile{
+            pos: file.offset,
+            file: Some(file),
+            cap: cap,
+            rbuf: Vec::new(),
+            rpos: 0,
+            wbuf: Vec::with_capacity(cap),
+        }
+    }
+
+    pub fn get_ref(&self) -> &File<W> {
+        self.file.as_ref().unwrap()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Flushes buffered writes and returns the file, positioned where this left off.
+    pub fn into_inner(mut self) -> Result<File<W>> {
+        try!(self.flush_writes());
+        let mut file = self.file.take().unwrap();
+        file.offset = self.pos;
+        Ok(file)
+    }
+
+    /// Flushes buffered writes and closes the file, reporting any error.
+    pub fn close(self) -> Result<()> {
+        try!(self.into_inner()).close()
+    }
+
+    fn flush_writes(&mut self) -> Result<()> {
+        if self.wbuf.is_empty() {
+            return Ok(());
+        }
+        let offset = self.pos - self.wbuf.len() as u64;
+        let result = self.file.as_ref().unwrap().write_all_at(&self.wbuf, offset);
+        // Like std's BufWriter, don't retry a failed write on drop.
+        self.wbuf.clear();
+        result
+    }
+
+    fn discard_reads(&mut self) {
+        self.rbuf.clear();
+        self.rpos = 0;
+    }
+}
+
+impl<W> BufRead for BufFile<W> where W : 'static + io::Write + Send {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        try!(self.flush_writes());
+        if self.rpos >= self.rbuf.len() {
+            self.rbuf.resize(self.cap, 0);
+            self.rpos = 0;
+            let n = match self.file.as_ref().unwrap().read_at(&mut self.rbuf, self.pos) {
+                Ok(n) => n,
+                Err(e) => {
+                    self.rbuf.clear();
+                    return Err(From::from(e));
+                },
+            };
+            self.rbuf.truncate(n);
+        }
+        Ok(&self.rbuf[self.rpos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = cmp::min(amt, self.rbuf.len() - self.rpos);
+        self.rpos += amt;
+        self.pos += amt as u64;
+    }
+}
+
+impl<W> Read for BufFile<W> where W : 'static + io::Write + Send {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Large reads skip the buffer, but are still limited to what the server accepts.
+        if self.rpos >= self.rbuf.len() && buf.len() >= self.cap {
+            try!(self.flush_writes());
+            let n = try!(self.file.as_ref().unwrap().read_at(&mut buf[..self.cap], self.pos));
+            self.pos += n as u64;
+            return Ok(n);
+        }
+        let n = {
+            let available = try!(self.fill_buf());
+            let n = cmp::min(available.len(), buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            n
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<W> Write for BufFile<W> where W : 'static + io::Write + Send {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.discard_reads();
+        if self.wbuf.len() + buf.len() > self.cap {
+            try!(self.flush_writes());
+        }
+        if buf.len() >= self.cap {
+            try!(self.file.as_ref().unwrap().write_all_at(buf, self.pos));
+        } else {
+            self.wbuf.extend_from_slice(buf);
+        }
+        self.pos += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(try!(self.flush_writes()))
+    }
+}
+
+impl<W> Seek for BufFile<W> where W : 'static + io::Write + Send {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        try!(self.flush_writes());
+        let new_pos = {
+            let file = self.file.as_mut().unwrap();
+            file.offset = self.pos;
+            try!(file.seek(pos))
+        };
+        // Keep the read buffer if the new position is still inside it.
+        let start = self.pos - self.rpos as u64;
+        if new_pos >= start && new_pos <= start + self.rbuf.len() as u64 {
+            self.rpos = (new_pos - start) as usize;
+        } else {
+            self.discard_reads();
+        }
+        self.pos = new_pos;
+        Ok(new_pos)
+    }
+}
+
+impl<W> Drop for BufFile<W> where W : 'static + io::Write + Send {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = self.flush_writes();
+        }
+    }
+}
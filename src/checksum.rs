@@ -0,0 +1,211 @@
+extern crate crypto;
+
+use self::crypto::digest::Digest;
+use self::crypto::md5::Md5;
+use self::crypto::sha1::Sha1;
+use self::crypto::sha2::{Sha224, Sha256, Sha384, Sha512};
+
+use std::cmp;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+use error::{Error, Result};
+use packets;
+use packets::{FileAttr, Receivable};
+use path::RemotePath;
+use {ClientSender, File, SSH_FXF_READ};
+
+const READ_CHUNK_SIZE : usize = 32768;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha224,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// The algorithm name used by the check-file extension.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Sha1 => "sha1",
+            HashAlgorithm::Sha224 => "sha224",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha384 => "sha384",
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    fn hasher(&self) -> Box<Digest> {
+        match *self {
+            HashAlgorithm::Md5 => Box::new(Md5::new()),
+            HashAlgorithm::Sha1 => Box::new(Sha1::new()),
+            HashAlgorithm::Sha224 => Box::new(Sha224::new()),
+            HashAlgorithm::Sha256 => Box::new(Sha256::new()),
+            HashAlgorithm::Sha384 => Box::new(Sha384::new()),
+            HashAlgorithm::Sha512 => Box::new(Sha512::new()),
+        }
+    }
+
+    /// Hashes everything `r` yields, e.g. a local copy of a transferred file.
+    pub fn digest<R>(&self, r: &mut R) -> io::Result<Vec<u8>> where R: io::Read {
+        let mut hasher = self.hasher();
+        let mut buf = vec![0; READ_CHUNK_SIZE];
+        loop {
+            let n = try!(r.read(&mut buf));
+            if n == 0 {
+                break;
+            }
+            hasher.input(&buf[..n]);
+        }
+        Ok(result(&mut *hasher))
+    }
+}
+
+// Hashes data handed over piece by piece, for contents that are never all in memory at once.
+pub struct Hasher(Box<Digest>);
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Hasher {
+        Hasher(algorithm.hasher())
+    }
+
+    pub fn input(&mut self, data: &[u8]) {
+        self.0.input(data);
+    }
+
+    pub fn result(mut self) -> Vec<u8> {
+        result(&mut *self.0)
+    }
+}
+
+fn result(hasher: &mut Digest) -> Vec<u8> {
+    let mut out = vec![0; hasher.output_bytes()];
+    hasher.result(&mut out);
+    out
+}
+
+// Converts a range into the (start-offset, length) pair the extensions expect, where a length of
+// zero means until the end of the file.
+fn offsets(range: &Option<Range<u64>>) -> (u64, u64) {
+    match *range {
+        Some(ref range) => (range.start, range.end.saturating_sub(range.start)),
+        None => (0, 0),
+    }
+}
+
+pub fn checksum_path<W>(sender: &Arc<ClientSender<W>>, path: Vec<u8>, algorithm: HashAlgorithm,
+                        range: Option<Range<u64>>) -> Result<Vec<u8>> where W : 'static + io::Write + Send {
+    let (start, len) = offsets(&range);
+    if range.is_some() && len == 0 {
+        return Ok(result(&mut *algorithm.hasher()));
+    }
+    if let Some(hash) = try!(checksum_extension(sender, packets::CHECK_FILE_NAME, packets::MD5_HASH,
+                                                 path.clone(), algorithm, start, len)) {
+        return Ok(hash);
+    }
+    let file = try!(File::open(sender.clone(), path, SSH_FXF_READ, FileAttr::new()));
+    hash_file(&file, algorithm, start, len)
+}
+
+// Fails with `ChecksumMismatch` unless the checksum of `path`, or of a range of it, is `expected`.
+pub fn verify_path<W>(sender: &Arc<ClientSender<W>>, path: &RemotePath, algorithm: HashAlgorithm,
+                      range: Option<Range<u64>>, expected: &[u8]) -> Result<()> where W : 'static + io::Write + Send {
+    if try!(checksum_path(sender, try!(sender.encode_path(path)), algorithm, range)) != expected {
+        return Err(Error::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+pub fn checksum_handle<W>(file: &File<W>, algorithm: HashAlgorithm, range: Option<Range<u64>>) -> Result<Vec<u8>>
+    where W : 'static + io::Write + Send {
+    let (start, len) = offsets(&range);
+    if range.is_some() && len == 0 {
+        return Ok(result(&mut *algorithm.hasher()));
+    }
+    if let Some(hash) = try!(checksum_extension(&file.client, packets::CHECK_FILE_HANDLE, packets::MD5_HASH_HANDLE,
+                                                 file.handle.clone(), algorithm, start, len)) {
+        return Ok(hash);
+    }
+    hash_file(file, algorithm, start, len)
+}
+
+// Asks the server to compute the hash. Returns None when it has no suitable extension.
+fn checksum_extension<W>(sender: &Arc<ClientSender<W>>, check_file: &'static str, md5_hash: &'static str,
+                         name: Vec<u8>, algorithm: HashAlgorithm, start: u64, len: u64) -> Result<Option<Vec<u8>>>
+    where W : 'static + io::Write + Send {
+    if sender.has_extension(check_file) {
+        let p = packets::FxpCheckFile{
+            extension: check_file,
+            name: name.clone(),
+            hash_algorithms: algorithm.name().into(),
+            start_offset: start,
+            length: len,
+            block_size: 0,
+        };
+        if let Some(data) = try!(extended_reply(try!(sender.send_receive(&p)))) {
+            let mut r = io::Cursor::new(data);
+            try!(Vec::<u8>::recv(&mut r));  // "check-file"
+            let used = try!(Vec::<u8>::recv(&mut r));
+            if used != algorithm.name().as_bytes() {
+                return Err(Error::UnexpectedData);
+            }
+            let start = r.position() as usize;
+            return Ok(Some(r.into_inner().split_off(start)));
+        }
+    }
+    if algorithm == HashAlgorithm::Md5 && sender.has_extension(md5_hash) {
+        let p = packets::FxpMd5Hash{
+            extension: md5_hash,
+            name: name,
+            start_offset: start,
+            length: len,
+            quick_check_hash: Vec::new(),
+        };
+        if let Some(data) = try!(extended_reply(try!(sender.send_receive(&p)))) {
+            let mut r = io::Cursor::new(data);
+            try!(Vec::<u8>::recv(&mut r));  // "md5-hash"
+            return Ok(Some(try!(Vec::<u8>::recv(&mut r))));
+        }
+    }
+    Ok(None)
+}
+
+fn extended_reply(resp: packets::SftpResponsePacket) -> Result<Option<Vec<u8>>> {
+    match resp {
+        packets::SftpResponsePacket::ExtendedReply(reply) => Ok(Some(reply.data)),
+        packets::SftpResponsePacket::Status(packets::FxpStatus{code:
+            packets::FxpStatusCode::OpUnsupported, msg: _}) => Ok(None),
+        packets::SftpResponsePacket::Status(status) => Err(Error::FromServer(Box::new(status))),
+        x => Err(Error::UnexpectedResponse(Box::new(x))),
+    }
+}
+
+fn hash_file<W>(file: &File<W>, algorithm: HashAlgorithm, start: u64, len: u64) -> Result<Vec<u8>>
+    where W : 'static + io::Write + Send {
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0; READ_CHUNK_SIZE];
+    let end = if len == 0 { None } else { Some(start + len) };
+    let mut offset = start;
+    loop {
+        let want = match end {
+            Some(end) => cmp::min(end - offset, buf.len() as u64) as usize,
+            None => buf.len(),
+        };
+        if want == 0 {
+            break;
+        }
+        let n = try!(file.read_at(&mut buf[..want], offset));
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+        offset += n as u64;
+    }
+    Ok(result(&mut *hasher))
+}
@@ -0,0 +1,80 @@
+extern crate encoding;
+
+use self::encoding::{DecoderTrap, EncoderTrap, EncodingRef};
+use self::encoding::label::encoding_from_whatwg_label;
+
+use std::fmt;
+
+use error::{Error, Result};
+use path::{RemotePath, RemotePathBuf};
+
+/// How filenames are encoded on the wire. `RemotePath`s built from Rust strings hold UTF-8, so
+/// this decides how they are converted for servers that use something else.
+#[derive(Clone)]
+pub enum FilenameEncoding {
+    /// Filenames are UTF-8, as required by newer versions of the protocol, and are passed
+    /// through as raw bytes without validation. Version 3 servers such as OpenSSH send whatever
+    /// bytes the filesystem holds, so names that are not valid UTF-8 come back unchanged and can
+    /// be used in later requests.
+    Utf8,
+    /// Filenames use a legacy character set such as Latin-1 or Shift-JIS and are converted to
+    /// and from UTF-8. Names that cannot be converted are rejected.
+    Legacy(EncodingRef),
+}
+
+impl FilenameEncoding {
+    /// Looks up a legacy encoding by its WHATWG label, e.g. "latin1" or "shift_jis".
+    pub fn from_label(label: &str) -> Option<FilenameEncoding> {
+        encoding_from_whatwg_label(label).map(FilenameEncoding::Legacy)
+    }
+
+    pub fn encode(&self, path: &RemotePath) -> Result<Vec<u8>> {
+        match *self {
+            FilenameEncoding::Utf8 => Ok(path.as_bytes().to_vec()),
+            FilenameEncoding::Legacy(enc) => {
+                let s = match path.to_str() {
+                    Some(s) => s,
+                    None => return Err(Error::InvalidFilename(path.as_bytes().to_vec())),
+                };
+                enc.encode(s, EncoderTrap::Strict).map_err(|_| Error::InvalidFilename(path.as_bytes().to_vec()))
+            },
+        }
+    }
+
+    pub fn decode(&self, name: Vec<u8>) -> Result<RemotePathBuf> {
+        match *self {
+            FilenameEncoding::Utf8 => Ok(RemotePathBuf::from(name)),
+            FilenameEncoding::Legacy(enc) => match enc.decode(&name, DecoderTrap::Strict) {
+                Ok(s) => Ok(RemotePathBuf::from(s)),
+                Err(_) => Err(Error::InvalidFilename(name)),
+            },
+        }
+    }
+
+    // The longname is only informational, so it is never rejected; characters that cannot be
+    // converted are replaced instead.
+    pub fn decode_lossy(&self, s: Vec<u8>) -> Vec<u8> {
+        match *self {
+            FilenameEncoding::Utf8 => s,
+            FilenameEncoding::Legacy(enc) => match enc.decode(&s, DecoderTrap::Replace) {
+                Ok(s) => s.into_bytes(),
+                Err(_) => s,
+            },
+        }
+    }
+}
+
+impl Default for FilenameEncoding {
+    fn default() -> FilenameEncoding {
+        FilenameEncoding::Utf8
+    }
+}
+
+impl fmt::Debug for FilenameEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FilenameEncoding::Utf8 => write!(f, "Utf8"),
+            FilenameEncoding::Legacy(enc) => write!(f, "Legacy({})", enc.name()),
+        }
+    }
+}
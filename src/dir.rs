@@ -0,0 +1,389 @@
+use std::cmp::Ordering;
+use std::io;
+use std::sync::Arc;
+
+use error::{Error, Result};
+use metadata::{FileType, Metadata};
+use packets;
+use packets::FileAttr;
+use path::{RemotePath, RemotePathBuf};
+use {Client, ClientSender, ReadDir};
+
+/// An entry returned by `ReadDir`.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    path: RemotePathBuf,
+    file_name: RemotePathBuf,
+    longname: Vec<u8>,
+    attrs: FileAttr,
+}
+
+impl DirEntry {
+    pub fn new(dir: &RemotePath, file_name: RemotePathBuf, longname: Vec<u8>, attrs: FileAttr) -> DirEntry {
+        DirEntry{
+            path: dir.join(&file_name),
+            file_name: file_name,
+            longname: longname,
+            attrs: attrs,
+        }
+    }
+
+    pub fn file_name(&self) -> &RemotePath {
+        &self.file_name
+    }
+
+    /// The directory that was read joined with the entry's name.
+    pub fn path(&self) -> &RemotePath {
+        &self.path
+    }
+
+    /// The attributes sent along with the entry. Servers may leave some of them out.
+    pub fn metadata(&self) -> Metadata {
+        Metadata::from(self.attrs.clone())
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.metadata().file_type()
+    }
+
+    /// The `ls -l` style line the server sent for this entry.
+    pub fn longname(&self) -> &[u8] {
+        &self.longname
+    }
+
+    pub fn parse_longname(&self) -> Option<LongName> {
+        LongName::parse(&String::from_utf8_lossy(&self.longname))
+    }
+
+    pub fn is_dot_or_dot_dot(&self) -> bool {
+        let name = self.file_name.as_bytes();
+        name == b"." || name == b".."
+    }
+}
+
+const MONTHS : [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun",
+                                     "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// The fields of an `ls -l` style longname that are not available from the attributes, such as
+/// owner and group names. The format is not standardized, so anything that cannot be recognized
+/// is left as None.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LongName {
+    pub permissions: String,
+    pub link_count: Option<u64>,
+    pub owner: Option<String>,
+    pub group: Option<String>,
+    pub size: Option<u64>,
+}
+
+fn is_number(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_digit(10))
+}
+
+impl LongName {
+    pub fn parse(longname: &str) -> Option<LongName> {
+        let fields : Vec<&str> = longname.split_whitespace().collect();
+        let permissions = match fields.first() {
+            Some(perms) if perms.len() >= 10 && perms.chars().next().map_or(false, |c| "-dlcbps".contains(c)) => {
+                perms.to_string()
+            },
+            _ => return None,
+        };
+        let mut i = 1;
+        let link_count = match fields.get(i) {
+            Some(field) if is_number(field) => {
+                i += 1;
+                field.parse().ok()
+            },
+            _ => None,
+        };
+        let owner = fields.get(i).map(|s| s.to_string());
+        i += 1;
+        // Some servers leave out the group, in which case the size directly precedes the date.
+        let group = match (fields.get(i), fields.get(i + 1)) {
+            (Some(size), Some(month)) if is_number(size) && MONTHS.contains(month) => None,
+            (Some(group), Some(_)) => {
+                i += 1;
+                Some(group.to_string())
+            },
+            _ => None,
+        };
+        let size = match fields.get(i) {
+            Some(field) if is_number(field) => field.parse().ok(),
+            _ => None,
+        };
+        Some(LongName{
+            permissions: permissions,
+            link_count: link_count,
+            owner: owner,
+            group: group,
+            size: size,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    /// Keep the order the server returned the entries in.
+    Unsorted,
+    Name,
+    Modified,
+    Size,
+}
+
+/// Controls `Client::list_dir`.
+#[derive(Debug, Clone)]
+pub struct ListOptions {
+    sort_by: SortBy,
+    reverse: bool,
+    dirs_first: bool,
+    follow_links: bool,
+    skip_dots: bool,
+}
+
+impl ListOptions {
+    pub fn new() -> ListOptions {
+        ListOptions{
+            sort_by: SortBy::Name,
+            reverse: false,
+            dirs_first: false,
+            follow_links: false,
+            skip_dots: true,
+        }
+    }
+
+    pub fn sort_by(&mut self, sort_by: SortBy) -> &mut ListOptions {
+        self.sort_by = sort_by;
+        self
+    }
+
+    pub fn reverse(&mut self, reverse: bool) -> &mut ListOptions {
+        self.reverse = reverse;
+        self
+    }
+
+    /// Lists directories before everything else, each group sorted on its own.
+    pub fn dirs_first(&mut self, dirs_first: bool) -> &mut ListOptions {
+        self.dirs_first = dirs_first;
+        self
+    }
+
+    /// Reports the attributes of symlink targets instead of the links themselves.
+    pub fn follow_links(&mut self, follow_links: bool) -> &mut ListOptions {
+        self.follow_links = follow_links;
+        self
+    }
+
+    pub fn skip_dots(&mut self, skip_dots: bool) -> &mut ListOptions {
+        self.skip_dots = skip_dots;
+        self
+    }
+}
+
+// Entries without a type, size or modification time are stat-ed again so they can be sorted and
+// displayed.
+fn incomplete(attrs: &FileAttr) -> bool {
+    attrs.perms.is_none() || attrs.size.is_none() || attrs.mtime.is_none()
+}
+
+pub fn list_dir<W>(sender: &Arc<ClientSender<W>>, mut read_dir: ReadDir<W>, options: &ListOptions) -> Result<Vec<DirEntry>>
+    where W : 'static + io::Write + Send {
+    read_dir.skip_dots(options.skip_dots);
+    let mut entries = Vec::new();
+    for entry in read_dir {
+        entries.push(try!(entry));
+    }
+
+    // Send all of the stat requests before waiting on any of them.
+    let mut pending = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let follow = options.follow_links && entry.file_type().is_symlink();
+        if !follow && !incomplete(&entry.attrs) {
+            continue;
+        }
+        let path = try!(sender.encode_path(&entry.path));
+        let rx = if follow || (options.follow_links && entry.attrs.perms.is_none()) {
+            try!(sender.send(&packets::FxpStat{path: path}))
+        } else {
+            try!(sender.send(&packets::FxpLStat{path: path}))
+        };
+        pending.push((i, rx));
+    }
+    for (i, rx) in pending {
+        match try!(rx.recv()) {
+            packets::SftpResponsePacket::Attrs(attrs) => entries[i].attrs = attrs,
+            // The entry may have gone away since it was listed, or be a dangling symlink; keep
+            // what the listing said about it.
+            packets::SftpResponsePacket::Status(_) => {},
+            x => return Err(Error::UnexpectedResponse(Box::new(x))),
+        }
+    }
+
+    sort(&mut entries, options);
+    Ok(entries)
+}
+
+fn sort(entries: &mut Vec<DirEntry>, options: &ListOptions) {
+    let sort_by = options.sort_by;
+    let reverse = options.reverse;
+    let dirs_first = options.dirs_first;
+    entries.sort_by(|a, b| {
+        if dirs_first {
+            match (a.file_type().is_dir(), b.file_type().is_dir()) {
+                (true, false) => return Ordering::Less,
+                (false, true) => return Ordering::Greater,
+                _ => {},
+            }
+        }
+        let ordering = match sort_by {
+            SortBy::Unsorted => Ordering::Equal,
+            SortBy::Name => a.file_name.cmp(&b.file_name),
+            SortBy::Modified => a.attrs.mtime.cmp(&b.attrs.mtime).then_with(|| a.file_name.cmp(&b.file_name)),
+            SortBy::Size => a.attrs.size.cmp(&b.attrs.size).then_with(|| a.file_name.cmp(&b.file_name)),
+        };
+        if reverse { ordering.reverse() } else { ordering }
+    });
+}
+
+fn at_path(path: &RemotePath, err: Error) -> Error {
+    match err {
+        Error::AtPath(..) => err,
+        err => Error::AtPath(path.to_remote_path_buf(), Box::new(err)),
+    }
+}
+
+fn is_dir<W>(client: &mut Client<W>, path: &RemotePath) -> bool where W : 'static + io::Write + Send {
+    client.metadata(path).map(|m| m.is_dir()).unwrap_or(false)
+}
+
+// Only directories that were actually created are reported to the progress.
+fn mkdir<W>(client: &mut Client<W>, path: &RemotePath, attrs: &FileAttr) -> Result<()>
+    where W : 'static + io::Write + Send {
+    try!(client.mkdir_with(path, attrs.clone()));
+    if let Some(tracker) = client.sender.tracker(path, None) {
+        tracker.finish();
+    }
+    Ok(())
+}
+
+// The error from mkdir does not say whether the directory already exists, so every failure is
+// followed by a stat. This also covers another client creating the directory concurrently.
+pub fn create_dir_all<W>(client: &mut Client<W>, path: &RemotePath, attrs: &FileAttr) -> Result<()>
+    where W : 'static + io::Write + Send {
+    if path.as_bytes().is_empty() {
+        return Ok(());
+    }
+    if mkdir(client, path, attrs).is_ok() || is_dir(client, path) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        try!(create_dir_all(client, parent, attrs));
+    }
+    match mkdir(client, path, attrs) {
+        Ok(()) => Ok(()),
+        Err(_) if is_dir(client, path) => Ok(()),
+        Err(e) => Err(at_path(path, e)),
+    }
+}
+
+pub fn remove_dir_all<W>(client: &mut Client<W>, path: &RemotePath) -> Result<()>
+    where W : 'static + io::Write + Send {
+    let metadata = try!(client.symlink_metadata(path).map_err(|e| at_path(path, e)));
+    if !metadata.is_dir() {
+        let sender = client.sender.clone();
+        return sender.track(path, None, |_| client.remove(path).map_err(|e| at_path(path, e)));
+    }
+    try!(remove_dir_contents(client, path));
+    rmdir(client, path)
+}
+
+fn rmdir<W>(client: &mut Client<W>, path: &RemotePath) -> Result<()> where W : 'static + io::Write + Send {
+    let sender = client.sender.clone();
+    sender.track(path, None, |_| client.rmdir(path).map_err(|e| at_path(path, e)))
+}
+
+// Removes everything but the subdirectories with one batch of concurrent requests, then recurses
+// into the subdirectories. Symlinks are removed, never followed.
+fn remove_dir_contents<W>(client: &mut Client<W>, dir: &RemotePath) -> Result<()>
+    where W : 'static + io::Write + Send {
+    let mut read_dir = try!(client.readdir(dir).map_err(|e| at_path(dir, e)));
+    read_dir.skip_dots(true);
+    let mut entries = Vec::new();
+    for entry in read_dir {
+        entries.push(try!(entry.map_err(|e| at_path(dir, e))));
+    }
+
+    let mut subdirs = Vec::new();
+    let mut pending = Vec::new();
+    for entry in entries {
+        let is_dir = match entry.attrs.perms {
+            Some(_) => entry.file_type().is_dir(),
+            None => try!(client.symlink_metadata(&entry.path).map_err(|e| at_path(&entry.path, e))).is_dir(),
+        };
+        if is_dir {
+            subdirs.push(entry.path);
+            continue;
+        }
+        let p = packets::FxpRemove{filename: try!(client.sender.encode_path(&entry.path))};
+        let tracker = client.sender.tracker(&entry.path, None);
+        let rx = try!(client.sender.send(&p).map_err(|e| at_path(&entry.path, e)));
+        pending.push((entry.path, rx, tracker));
+    }
+    // Wait for every outstanding request, even after a failure, and report the first one.
+    let mut result = Ok(());
+    for (path, rx, tracker) in pending {
+        let resp = rx.recv().and_then(Client::<W>::expect_status_response);
+        if let Some(tracker) = tracker {
+            tracker.finish();
+        }
+        if let Err(e) = resp {
+            if result.is_ok() {
+                result = Err(at_path(&path, e));
+            }
+        }
+    }
+    try!(result);
+
+    for subdir in subdirs {
+        try!(remove_dir_contents(client, &subdir));
+        try!(rmdir(client, &subdir));
+    }
+    Ok(())
+}
+
+/// Creates directories with initial attributes, modeled after `std::fs::DirBuilder`.
+pub struct DirBuilder<'a, W> where W: 'a {
+    client: &'a mut Client<W>,
+    recursive: bool,
+    attrs: FileAttr,
+}
+
+impl<'a, W> DirBuilder<'a, W> where W : 'static + io::Write + Send {
+    pub fn new(client: &'a mut Client<W>) -> DirBuilder<'a, W> {
+        DirBuilder{client: client, recursive: false, attrs: FileAttr::new()}
+    }
+
+    /// Also create missing parents, which get the same attributes.
+    pub fn recursive(&mut self, recursive: bool) -> &mut DirBuilder<'a, W> {
+        self.recursive = recursive;
+        self
+    }
+
+    pub fn mode(&mut self, mode: u32) -> &mut DirBuilder<'a, W> {
+        self.attrs.perms = Some(mode);
+        self
+    }
+
+    pub fn attrs(&mut self, attrs: FileAttr) -> &mut DirBuilder<'a, W> {
+        self.attrs = attrs;
+        self
+    }
+
+    pub fn create<P>(&mut self, path: P) -> Result<()> where P: AsRef<RemotePath> {
+        if self.recursive {
+            create_dir_all(self.client, path.as_ref(), &self.attrs)
+        } else {
+            self.client.mkdir_with(path, self.attrs.clone())
+        }
+    }
+}
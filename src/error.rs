@@ -9,6 +9,7 @@ use std::sync::Arc;
 use std::string::FromUtf8Error;
 
 use packets;
+use path::RemotePathBuf;
 
 pub type Result<T> = ::std::result::Result<T, Error>;
 
@@ -23,6 +24,13 @@ pub enum Error {
     MismatchedVersion(u32),
     FromServer(Box<packets::FxpStatus>),
     UnexpectedResponse(Box<packets::SftpResponsePacket>),
+    ChecksumMismatch,
+    InvalidFilename(Vec<u8>),
+    AtPath(RemotePathBuf, Box<Error>),
+    PacketTooLarge(u32),
+    StringTooLong(u32),
+    InvalidTrace(usize),
+    IncompleteAttrs(&'static str),
 }
 
 impl error::Error for Error {
@@ -37,6 +45,13 @@ impl error::Error for Error {
             Error::MismatchedVersion(_) => "Server responded with an incorrect version",
             Error::FromServer(_) => "Server responded with error",
             Error::UnexpectedResponse(_) => "Unexpected response",
+            Error::ChecksumMismatch => "Checksum mismatch",
+            Error::InvalidFilename(_) => "Filename can not be represented in the configured encoding",
+            Error::AtPath(_, ref err) => err.description(),
+            Error::PacketTooLarge(_) => "Server sent a packet larger than the configured maximum",
+            Error::StringTooLong(_) => "Server sent a string longer than the configured maximum",
+            Error::InvalidTrace(_) => "Not a valid trace record",
+            Error::IncompleteAttrs(_) => "Attributes set only one field of a pair that is sent together",
         }
     }
 
@@ -45,6 +60,7 @@ impl error::Error for Error {
             Error::ReceiverDisconnected(ref e) => Some(&***e),
             Error::Io(ref err) => err.cause(),
             Error::Utf8(ref err) => err.cause(),
+            Error::AtPath(_, ref err) => Some(&**err),
             _ => None,
         }
     }
@@ -62,6 +78,14 @@ impl fmt::Display for Error {
             Error::MismatchedVersion(ref ver) => write!(f, "Server responded with version {}. Only version 3 is supported.", *ver),
             Error::FromServer(ref err) => write!(f, "Server responded with: {}", **err),
             Error::UnexpectedResponse(_) => write!(f, "Unexpected response"),
+            Error::ChecksumMismatch => write!(f, "Checksum of the transferred data does not match the source"),
+            Error::InvalidFilename(ref name) => write!(f, "Filename can not be represented in the configured encoding: {}",
+                                                       String::from_utf8_lossy(name)),
+            Error::AtPath(ref path, ref err) => write!(f, "{}: {}", path.display(), **err),
+            Error::PacketTooLarge(len) => write!(f, "Server sent a packet of {} bytes, more than the configured maximum", len),
+            Error::StringTooLong(len) => write!(f, "Server sent a string of {} bytes, more than the configured maximum", len),
+            Error::InvalidTrace(line) => write!(f, "Line {} of the trace is not a valid record", line),
+            Error::IncompleteAttrs(pair) => write!(f, "Attributes have to set both or neither of {}", pair),
         }
     }
 }
@@ -81,6 +105,17 @@ impl From<ByteError> for Error {
     }
 }
 
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            Error::FromServer(status) => io::Error::from(*status),
+            Error::UnexpectedEOF => io::Error::new(io::ErrorKind::UnexpectedEof, err),
+            err => io::Error::new(io::ErrorKind::Other, err),
+        }
+    }
+}
+
 impl From<FromUtf8Error> for Error {
     fn from(err: FromUtf8Error) -> Error {
         Error::Utf8(err)
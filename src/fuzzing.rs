@@ -0,0 +1,164 @@
+//! Entry points for the fuzz targets in `fuzz/`. Only built with the "fuzzing" feature and not
+//! part of the stable API.
+
+use std::borrow::Cow;
+use std::io;
+
+use error::Error;
+use packets;
+use packets::{FileAttr, Receivable, Request};
+
+pub fn decode_file_attr(data: &[u8]) {
+    let _ = FileAttr::recv(&mut io::Cursor::new(data));
+}
+
+pub fn decode_name(data: &[u8]) {
+    let _ = packets::FxpName::recv(&mut io::Cursor::new(data));
+}
+
+pub fn decode_version(data: &[u8]) {
+    let _ = packets::FxpVersion::recv(&mut io::Cursor::new(data));
+}
+
+pub fn decode_status(data: &[u8]) {
+    let _ = packets::FxpStatus::recv(&mut io::Cursor::new(data));
+}
+
+pub fn decode_handle(data: &[u8]) {
+    let _ = packets::FxpHandle::recv(&mut io::Cursor::new(data));
+}
+
+pub fn decode_data(data: &[u8]) {
+    let _ = packets::FxpData::recv(&mut io::Cursor::new(data));
+}
+
+pub fn decode_limits(data: &[u8]) {
+    let _ = packets::Limits::recv(&mut io::Cursor::new(data));
+}
+
+/// Decodes framed packets until the input runs out or is rejected. Responses to odd request ids
+/// are decoded into a caller-supplied buffer, like `File::read_at` does.
+pub fn decode_stream(data: &[u8]) {
+    // Small limits, so that rejecting oversized input is exercised too.
+    let limits = packets::DecodeLimits{max_packet_len: 64 * 1024, max_string_len: 4096};
+    let mut r = io::Cursor::new(data);
+    loop {
+        let mut buf = [0; 512];
+        let mut buf = Some(&mut buf[..]);
+        let resp = packets::recv_with_buffer(&mut r, &limits, |req_id| if req_id % 2 == 1 { buf.take() } else { None });
+        if resp.is_err() {
+            return;
+        }
+    }
+}
+
+// Hands out request fields from the fuzzer's input, padding with zeros once it runs out.
+struct Input<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Input<'a> {
+    fn bytes(&mut self, n: usize) -> Vec<u8> {
+        let n = ::std::cmp::min(n, self.data.len());
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        head.to_vec()
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.bytes(1).first().cloned().unwrap_or(0)
+    }
+
+    fn u32(&mut self) -> u32 {
+        self.bytes(4).iter().fold(0, |acc, &b| acc << 8 | b as u32)
+    }
+
+    fn u64(&mut self) -> u64 {
+        (self.u32() as u64) << 32 | self.u32() as u64
+    }
+
+    fn string(&mut self) -> Vec<u8> {
+        let n = self.u8() as usize;
+        self.bytes(n)
+    }
+
+    fn opt_u32(&mut self) -> Option<u32> {
+        if self.u8() & 1 == 1 { Some(self.u32()) } else { None }
+    }
+
+    fn attrs(&mut self) -> FileAttr {
+        let mut attrs = FileAttr::new();
+        attrs.size = if self.u8() & 1 == 1 { Some(self.u64()) } else { None };
+        attrs.uid = self.opt_u32();
+        attrs.gid = self.opt_u32();
+        attrs.perms = self.opt_u32();
+        attrs.atime = self.opt_u32();
+        attrs.mtime = self.opt_u32();
+        for _ in 0..self.u8() % 4 {
+            attrs.extensions.push(packets::Extension{name: self.string(), data: self.string()});
+        }
+        attrs
+    }
+}
+
+// Every encoder has to produce exactly as many bytes as it claims, and writing the head and the
+// trailing data separately has to give the same bytes as writing it in one go.
+fn check<P: Request>(p: &P) {
+    let mut full = Vec::new();
+    match p.write_to(&mut full) {
+        Ok(()) => {},
+        // Attributes with only one of uid/gid or atime/mtime set are refused, whichever way the
+        // packet is written.
+        Err(Error::IncompleteAttrs(_)) => {
+            assert!(p.write_head_to(&mut Vec::new()).is_err(), "{:?}", p);
+            return;
+        },
+        Err(e) => panic!("{:?}: {}", p, e),
+    }
+    assert_eq!(p.size() as usize, full.len(), "{:?}", p);
+    let mut split = Vec::new();
+    p.write_head_to(&mut split).unwrap();
+    split.extend_from_slice(p.trailing_data());
+    assert_eq!(full, split, "{:?}", p);
+}
+
+/// Builds requests from the input and checks the encoders' invariants.
+pub fn encode_requests(data: &[u8]) {
+    let mut input = Input{data: data};
+    while !input.data.is_empty() {
+        match input.u8() % 12 {
+            0 => check(&packets::FxpOpen{filename: input.string(), pflags: input.u32(), attrs: input.attrs()}),
+            1 => check(&packets::FxpRead{handle: input.string(), offset: input.u64(), len: input.u32()}),
+            2 => {
+                let handle = input.string();
+                let offset = input.u64();
+                let data = input.string();
+                check(&packets::FxpWrite{handle: Cow::Borrowed(&handle), offset: offset, data: Cow::Borrowed(&data)});
+            },
+            3 => check(&packets::FxpSetStat{path: input.string(), attrs: input.attrs()}),
+            4 => check(&packets::FxpFSetStat{handle: input.string(), attrs: input.attrs()}),
+            5 => check(&packets::FxpMkDir{path: input.string(), attrs: input.attrs()}),
+            6 => check(&packets::FxpRename{oldpath: input.string(), newpath: input.string()}),
+            7 => check(&packets::FxpCopyData{
+                read_handle: input.string(),
+                read_offset: input.u64(),
+                read_len: input.u64(),
+                write_handle: input.string(),
+                write_offset: input.u64(),
+            }),
+            8 => check(&packets::FxpCopyFile{source: input.string(), destination: input.string(),
+                                             overwrite: input.u8() & 1 == 1}),
+            9 => check(&packets::FxpCheckFile{
+                extension: packets::CHECK_FILE_NAME,
+                name: input.string(),
+                hash_algorithms: input.string(),
+                start_offset: input.u64(),
+                length: input.u64(),
+                block_size: input.u32(),
+            }),
+            10 => check(&packets::FxpPosixRename{oldpath: input.string(), newpath: input.string()}),
+            _ => check(&packets::FxpInit{version: input.u32(), extensions: vec![
+                packets::Extension{name: input.string(), data: input.string()}]}),
+        }
+    }
+}
@@ -5,28 +5,97 @@
 #![feature(clone_from_slice)]
 
 extern crate byteorder;
+extern crate crypto;
+extern crate encoding;
+#[cfg(all(feature = "log", not(feature = "tracing")))]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
 
+#[cfg(not(any(feature = "log", feature = "tracing")))]
+#[macro_use]
+mod logging;
+mod spans;
 mod packets;
 mod error;
+mod transfer;
+mod progress;
+mod ratelimit;
+mod checksum;
+mod metadata;
+mod path;
+mod codec;
+mod dir;
+mod replace;
+mod buffered;
+pub mod protocol;
+mod trace;
+mod metrics;
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
 
+use std::borrow::Cow;
 use std::io;
-use error::Result;
-use packets::Sendable;
+use packets::Receivable;
+use packets::{SSH_FXF_READ, SSH_FXF_WRITE, SSH_FXF_APPEND, SSH_FXF_CREAT, SSH_FXF_TRUNC, SSH_FXF_EXCL};
 use std::io::Write;
-use packets::Request;
 use std::thread;
 use std::sync::{Arc, Mutex, MutexGuard, atomic};
 use std::collections::HashMap;
 use std::sync::mpsc;
+use std::ops::Range;
+use std::slice;
+use std::cmp;
+use std::time::Instant;
+use progress::Tracker;
 
-pub use packets::FileAttr;
+pub use error::{Error, Result};
+pub use packets::{FileAttr, Limits, DecodeLimits};
+pub use transfer::SegmentedDownload;
+pub use progress::{Progress, TransferStatus, WithProgress};
+pub use ratelimit::{RateLimiter, Throttled, Clock, SystemClock};
+pub use checksum::HashAlgorithm;
+pub use metadata::{Metadata, FileType, Permissions};
+pub use path::{RemotePath, RemotePathBuf, Component, Components};
+pub use codec::FilenameEncoding;
+pub use replace::AtomicWrite;
+pub use buffered::BufFile;
+pub use metrics::{Stats, Histogram, StatsSink, PrometheusSink, LATENCY_BUCKETS};
+pub use trace::{Tracer, TraceFormat, TraceReader, TraceWriter, Replay, ReplayReader, ReplayWriter};
+pub use dir::{DirEntry, LongName, ListOptions, SortBy, DirBuilder};
 
 type ReqId = u32;
-type ReqMap = HashMap<ReqId, mpsc::Sender<Result<packets::SftpResponsePacket>>>;
+type ReqMap = HashMap<ReqId, PendingRequest>;
+
+struct PendingRequest {
+    tx: mpsc::Sender<Result<packets::Received>>,
+    data_buffer: Option<DataBuffer>,
+    // For logs and latency statistics.
+    name: &'static str,
+    sent: Instant,
+    span: spans::RequestSpan,
+}
+
+// A caller's buffer that the receiver thread decodes SSH_FXP_DATA into directly. The receiver
+// takes it out of the `PendingRequest` while decoding, and the caller stays blocked until the
+// receiver is done with it, see `send_request` and `send_receive_into`.
+struct DataBuffer {
+    ptr: *mut u8,
+    len: usize,
+}
+
+unsafe impl Send for DataBuffer {}
 
 struct ReceiverState {
     requests: ReqMap,
     recv_error: Option<Arc<Box<error::Error>>>,
+    decode_limits: packets::DecodeLimits,
+    stats: metrics::Recorder,
 }
 
 struct ClientReceiver<R> {
@@ -38,24 +107,56 @@ impl<R> ClientReceiver<R> where R : 'static + io::Read + Send {
     fn recv(&self) {
         let mut r = self.r.lock().unwrap();
         loop {
-            let resp = match packets::recv(&mut *r) {
+            let state = &self.state;
+            let limits = state.lock().unwrap().decode_limits;
+            let resp = packets::recv_with_buffer(&mut *r, &limits, |req_id| {
+                let mut state = state.lock().unwrap();
+                // The requester is blocked until the response is sent, so the buffer is valid and
+                // nothing else touches it until then.
+                state.requests.get_mut(&req_id).and_then(|req| req.data_buffer.take()).map(|buf| {
+                    unsafe { slice::from_raw_parts_mut(buf.ptr, buf.len) }
+                })
+            });
+            let (req_id, received) = match resp {
                 Err(e) => { Self::broadcast_error(&mut self.state.lock().unwrap(), e); return; },
                 Ok(x) => x,
             };
             let mut state = self.state.lock().unwrap();
-            match state.requests.remove(&resp.req_id) {
-                Some(tx) => {
-                    tx.send(Ok(resp.packet)).unwrap();
+            match state.requests.remove(&req_id) {
+                Some(req) => {
+                    let latency = req.sent.elapsed();
+                    let status = match received {
+                        packets::Received::Packet(packets::SftpResponsePacket::Status(ref status)) => status.code.name(),
+                        ref received => packets::msg_type_name(received.msg_type()),
+                    };
+                    req.span.finish(latency, status);
+                    req.span.in_scope(|| debug!("request {}: {} -> {} in {:?}", req_id, req.name, status, latency));
+                    match received {
+                        packets::Received::Packet(ref packet) => state.stats.response_received(req.name, latency, packet),
+                        packets::Received::DataInBuffer(n) => state.stats.data_received(req.name, latency, n),
+                    }
+                    // The requester may have given up on the response, e.g. when a pipelined
+                    // transfer bails out early.
+                    let _ = req.tx.send(Ok(received));
                 },
-                None => { Self::broadcast_error(&mut state, error::Error::NoMatchingRequest(resp.req_id)); return; },
+                None => { Self::broadcast_error(&mut state, error::Error::NoMatchingRequest(req_id)); return; },
             }
         }
     }
 
     fn broadcast_error(state: &mut MutexGuard<ReceiverState>, e: error::Error) {
+        match e {
+            error::Error::UnexpectedEOF => info!("connection closed by the server"),
+            error::Error::Io(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => info!("connection closed by the server"),
+            ref e => error!("receiver stopped: {}", e),
+        }
+        if !state.requests.is_empty() {
+            warn!("failing {} outstanding requests", state.requests.len());
+        }
         let arc_wrapped = Arc::new(Box::new(e));
-        for (_, tx) in state.requests.iter() {
-            tx.send(Err(error::Error::ReceiverDisconnected(arc_wrapped.clone()))).unwrap();
+        for (_, req) in state.requests.iter() {
+            req.span.finish(req.sent.elapsed(), "disconnected");
+            let _ = req.tx.send(Err(error::Error::ReceiverDisconnected(arc_wrapped.clone())));
         }
         state.requests.clear();
         state.recv_error = Some(arc_wrapped.clone());
@@ -63,52 +164,211 @@ impl<R> ClientReceiver<R> where R : 'static + io::Read + Send {
 }
 
 struct ClientSender<W> {
-    w: Mutex<W>,
+    w: Mutex<protocol::PacketWriter<W>>,
     recv_state: Arc<Mutex<ReceiverState>>,
     req_id: atomic::AtomicUsize,
+    limiter: Mutex<Option<RateLimiter>>,
+    progress: Mutex<Option<Arc<Progress>>>,
+    verify: Mutex<Option<HashAlgorithm>>,
+    extensions: Vec<packets::Extension>,
+    limits: packets::Limits,
+    encoding: Mutex<FilenameEncoding>,
 }
 
+// Every server has to accept packets of at least 34000 bytes, which leaves room for this much
+// data in a read or write.
+const DEFAULT_IO_LEN : u64 = 32768;
+// Room for everything but the data in a read response or write request.
+const PACKET_OVERHEAD : u64 = 1024;
+
 impl<W> ClientSender<W> where W : 'static + io::Write + Send {
+    fn has_extension(&self, name: &str) -> bool {
+        self.extensions.iter().any(|e| e.name == name.as_bytes())
+    }
+
+    fn encode_path(&self, path: &RemotePath) -> Result<Vec<u8>> {
+        self.encoding.lock().unwrap().encode(path)
+    }
+
+    fn decode_path(&self, name: Vec<u8>) -> Result<RemotePathBuf> {
+        self.encoding.lock().unwrap().decode(name)
+    }
+
+    // Starts reporting a transfer of `path` to the progress set with `Client::set_progress`.
+    fn tracker(&self, path: &RemotePath, total: Option<u64>) -> Option<Tracker> {
+        self.progress.lock().unwrap().as_ref().map(|p| Tracker::new(p.clone(), path.to_string_lossy().into_owned(), total))
+    }
+
+    // Runs `f` with a tracker for `path`, reporting the transfer as finished whether or not it
+    // succeeded.
+    fn track<T, F>(&self, path: &RemotePath, total: Option<u64>, f: F) -> Result<T> where F: FnOnce(Option<&Tracker>) -> Result<T> {
+        let tracker = self.tracker(path, total);
+        let result = f(tracker.as_ref());
+        if let Some(tracker) = tracker {
+            tracker.finish();
+        }
+        result
+    }
+
+    // The most data to ask for in one read.
+    fn max_read_len(&self) -> usize {
+        self.io_len(self.limits.max_read_len)
+    }
+
+    // The most data to send in one write.
+    fn max_write_len(&self) -> usize {
+        self.io_len(self.limits.max_write_len)
+    }
+
+    fn io_len(&self, limit: u64) -> usize {
+        let mut len = if limit > 0 { limit } else { DEFAULT_IO_LEN };
+        if self.limits.max_packet_len > PACKET_OVERHEAD {
+            len = cmp::min(len, self.limits.max_packet_len - PACKET_OVERHEAD);
+        }
+        // Responses also have to fit what this side accepts.
+        let decode = self.recv_state.lock().unwrap().decode_limits;
+        let accepted = cmp::min(decode.max_packet_len as u64, decode.max_string_len as u64 + PACKET_OVERHEAD);
+        len = cmp::min(len, accepted.saturating_sub(PACKET_OVERHEAD));
+        cmp::max(len, 1) as usize
+    }
+
+    // Asks for the server's limits. Runs before the receiver thread takes over the reader.
+    fn query_limits<R: io::Read>(&self, r: &mut R) -> Result<packets::Limits> {
+        let req_id = self.next_id();
+        let sent = Instant::now();
+        try!(self.write_packet(req_id, &packets::FxpLimits));
+        self.count_sent(&packets::FxpLimits);
+        let resp = try!(self.recv_direct(r, packets::LIMITS, sent));
+        if resp.req_id != req_id {
+            return Err(error::Error::NoMatchingRequest(resp.req_id));
+        }
+        match resp.packet {
+            packets::SftpResponsePacket::ExtendedReply(reply) => packets::Limits::recv(&mut io::Cursor::new(reply.data)),
+            packets::SftpResponsePacket::Status(_) => Ok(packets::Limits::default()),
+            x => Err(error::Error::UnexpectedResponse(Box::new(x))),
+        }
+    }
+
     fn next_id(&self) -> ReqId {
         self.req_id.fetch_add(1, atomic::Ordering::Relaxed) as ReqId
     }
 
     fn send_init(&self) -> Result<()> {
         let init_packet = packets::FxpInit{version: 3, extensions: Vec::new()};
-        let mut w = self.w.lock().unwrap();
-        let n = packets::FxpInit::msg_type().size() + init_packet.size();
-        try!(n.write_to(&mut *w));
-        try!(packets::FxpInit::msg_type().write_to(&mut *w));
-        try!(init_packet.write_to(&mut *w));
+        try!(self.w.lock().unwrap().write_init(&init_packet));
+        self.count_sent(&init_packet);
         Ok(())
     }
 
-    fn send<P : packets::Request>(&self, packet : &P) -> Result<mpsc::Receiver<Result<packets::SftpResponsePacket>>> {
+    // Reads a response before the receiver thread has taken over the reader, and counts it like
+    // the receiver would.
+    fn recv_direct<R: io::Read>(&self, r: &mut R, name: &'static str, sent: Instant) -> Result<packets::SftpResponse> {
+        let resp = try!(packets::recv(r, &packets::DecodeLimits::default()));
+        self.recv_state.lock().unwrap().stats.response_received(name, sent.elapsed(), &resp.packet);
+        Ok(resp)
+    }
+
+    // Only requests that were written out completely are counted.
+    fn count_sent<P : packets::Request>(&self, packet: &P) {
+        self.recv_state.lock().unwrap().stats.request_sent(packet.name(), P::msg_type(), packet.payload_len());
+    }
+
+    fn write_packet<P : packets::Request>(&self, req_id: ReqId, packet: &P) -> Result<()> {
+        self.w.lock().unwrap().write_request(req_id, packet)
+    }
+
+    fn send<P : packets::Request>(&self, packet : &P) -> Result<Pending> {
+        Ok(Pending{rx: try!(self.send_request(packet, None))})
+    }
+
+    fn send_request<P : packets::Request>(&self, packet : &P, data_buffer: Option<DataBuffer>) ->
+        Result<mpsc::Receiver<Result<packets::Received>>> {
+        if packet.payload_len() > 0 {
+            let limiter = self.limiter.lock().unwrap().clone();
+            if let Some(limiter) = limiter {
+                limiter.acquire(packet.payload_len());
+            }
+        }
         let req_id = self.next_id();
         let (tx, rx) = mpsc::channel();
+        let has_buffer = data_buffer.is_some();
+        let span = spans::RequestSpan::new(packet.name(), req_id, packet.path());
         {
             let mut recv_state = self.recv_state.lock().unwrap();
             if let Some(ref e) = recv_state.recv_error {
                 return Err(error::Error::ReceiverDisconnected(e.clone()));
             }
-            recv_state.requests.insert(req_id, tx);
+            recv_state.requests.insert(req_id, PendingRequest{
+                tx: tx,
+                data_buffer: data_buffer,
+                name: packet.name(),
+                sent: Instant::now(),
+                span: span.clone(),
+            });
+        }
+        if let Err(e) = self.write_packet(req_id, packet) {
+            span.in_scope(|| warn!("request {}: {} could not be sent: {}", req_id, packet.name(), e));
+            // The server never saw the whole request, so it should not answer it. One that does
+            // anyway may already be decoding into the caller's buffer, in which case the caller
+            // has to wait for it to finish.
+            let in_use = {
+                let mut recv_state = self.recv_state.lock().unwrap();
+                let in_use = recv_state.requests.get(&req_id).map_or(false, |req| has_buffer && req.data_buffer.is_none());
+                if !in_use {
+                    recv_state.requests.remove(&req_id);
+                }
+                in_use
+            };
+            if in_use {
+                let _ = rx.recv();
+            }
+            return Err(e);
         }
-        let mut w = self.w.lock().unwrap();
-        let n = P::msg_type().size() + req_id.size() + packet.size();
-        try!(n.write_to(&mut *w));
-        try!(P::msg_type().write_to(&mut *w));
-        try!(req_id.write_to(&mut *w));
-        try!(packet.write_to(&mut *w));
-        //writeln!(&mut io::stderr(), "Send Request: {:?}", *packet);
+        self.count_sent(packet);
+        span.in_scope(|| match packet.path() {
+            Some(path) => debug!("request {}: {} {}", req_id, packet.name(), String::from_utf8_lossy(path)),
+            None => trace!("request {}: {}", req_id, packet.name()),
+        });
         Ok(rx)
     }
 
+    // Like `send_receive`, but SSH_FXP_DATA is decoded straight into `buf` and returned as
+    // `DataInBuffer`. Blocking here until the response arrives is what keeps `buf` borrowed for
+    // as long as the receiver thread may write to it.
+    fn send_receive_into<P : packets::Request>(&self, packet : &P, buf: &mut [u8]) ->
+        Result<packets::Received> {
+        let data_buffer = DataBuffer{ptr: buf.as_mut_ptr(), len: buf.len()};
+        let rx = try!(self.send_request(packet, Some(data_buffer)));
+        // Once the receiver has dropped its end, it can no longer write to `buf` either.
+        rx.recv().unwrap_or_else(|_| Err(receiver_gone()))
+    }
+
     fn send_receive<P : packets::Request>(&self, packet : &P) ->
         Result<packets::SftpResponsePacket> {
             let rx = try!(self.send(packet));
-            let resp = rx.recv().unwrap();
-            //writeln!(&mut io::stderr(), "Received Response: {:?}", resp);
-            resp
+            rx.recv()
+    }
+}
+
+// The receiver thread dropped a request without answering it, which only happens if it stopped
+// abnormally.
+fn receiver_gone() -> error::Error {
+    let e = io::Error::new(io::ErrorKind::BrokenPipe, "receiver thread stopped without answering");
+    error::Error::ReceiverDisconnected(Arc::new(Box::new(error::Error::Io(e))))
+}
+
+// The response to a request sent with `ClientSender::send`.
+struct Pending {
+    rx: mpsc::Receiver<Result<packets::Received>>,
+}
+
+impl Pending {
+    fn recv(&self) -> Result<packets::SftpResponsePacket> {
+        match try!(self.rx.recv().unwrap_or_else(|_| Err(receiver_gone()))) {
+            packets::Received::Packet(packet) => Ok(packet),
+            // Only requests sent with a buffer are answered like this.
+            packets::Received::DataInBuffer(_) => unreachable!(),
+        }
     }
 }
 
@@ -118,22 +378,40 @@ pub struct Client<W> {
 
 impl<W> Client<W> where W : 'static + io::Write + Send {
 	pub fn new<R>(mut r: R, w: W) -> Result<Client<W>> where R : 'static + io::Read + Send {
-        let s = ClientSender{
-            w: Mutex::new(w),
-            recv_state: Arc::new(Mutex::new(ReceiverState{requests: HashMap::new(), recv_error: None})),
+        let mut s = ClientSender{
+            w: Mutex::new(protocol::PacketWriter::new(w)),
+            recv_state: Arc::new(Mutex::new(ReceiverState{
+                requests: HashMap::new(),
+                recv_error: None,
+                decode_limits: packets::DecodeLimits::default(),
+                stats: metrics::Recorder::new(),
+            })),
             req_id: atomic::AtomicUsize::new(0),
+            limiter: Mutex::new(None),
+            progress: Mutex::new(None),
+            verify: Mutex::new(None),
+            extensions: Vec::new(),
+            limits: packets::Limits::default(),
+            encoding: Mutex::new(FilenameEncoding::default()),
         };
+        let sent = Instant::now();
         try!(s.send_init());
-        let resp = try!(packets::recv(&mut r));
-        //writeln!(&mut io::stderr(), "Received Response: {:?}", resp);
+        let resp = try!(s.recv_direct(&mut r, packets::msg_type_name(packets::SSH_FXP_INIT), sent));
         match resp.packet {
             packets::SftpResponsePacket::Version(x) => {
                 if x.version != 3 {
                     return Err(error::Error::MismatchedVersion(x.version));
                 }
+                s.extensions = x.extensions;
+                info!("connected, server supports version 3 with extensions {:?}",
+                      s.extensions.iter().map(|e| String::from_utf8_lossy(&e.name).into_owned()).collect::<Vec<_>>());
             },
             x => return Err(error::Error::UnexpectedResponse(Box::new(x))),
         }
+        if s.has_extension(packets::LIMITS) {
+            s.limits = try!(s.query_limits(&mut r));
+            debug!("server limits: {:?}", s.limits);
+        }
         let r = ClientReceiver{
             r: Mutex::new(r),
             state: s.recv_state.clone(),
@@ -142,16 +420,81 @@ impl<W> Client<W> where W : 'static + io::Write + Send {
         Ok(Client{sender: Arc::new(s)})
 	}
 
-    pub fn stat<S>(&mut self, path: S) -> Result<packets::FileAttr> where S: Into<String> {
-        let p = packets::FxpStat{path: path.into().into()};
+    /// The limits the server reported via limits@openssh.com, or all zeros if it does not
+    /// support the extension. Reads and writes are split to stay within them.
+    pub fn limits(&self) -> Limits {
+        self.sender.limits
+    }
+
+    /// Whether the server advertised the named protocol extension when the connection was
+    /// established.
+    pub fn has_extension(&self, name: &str) -> bool {
+        self.sender.has_extension(name)
+    }
+
+    /// Sets how paths are converted to and from the filenames the server uses. Defaults to UTF-8.
+    pub fn set_filename_encoding(&mut self, encoding: FilenameEncoding) {
+        *self.sender.encoding.lock().unwrap() = encoding;
+    }
+
+    /// Throttles the file contents read and written over this connection. The limiter may be
+    /// shared with other clients and its rate changed while transfers are running.
+    pub fn set_rate_limiter(&mut self, limiter: Option<RateLimiter>) {
+        *self.sender.limiter.lock().unwrap() = limiter;
+    }
+
+    /// Reports the progress of `copy`, `copy_range`, `read`, `write`, `append` and atomic writes,
+    /// one transfer per call. `create_dir_all` and `remove_dir_all` report every directory they
+    /// create and every entry they remove as a transfer of its own, without any bytes.
+    /// `SegmentedDownload::progress` takes precedence for segmented downloads.
+    pub fn set_progress(&mut self, progress: Option<Arc<Progress>>) {
+        *self.sender.progress.lock().unwrap() = progress;
+    }
+
+    /// Checks the result of `copy`, `copy_range`, `read`, `write`, `append` and atomic writes
+    /// against a checksum computed like `checksum` does, failing with `Error::ChecksumMismatch`
+    /// if they differ. Also applies to segmented downloads without a `verify` of their own.
+    pub fn set_verify(&mut self, algorithm: Option<HashAlgorithm>) {
+        *self.sender.verify.lock().unwrap() = algorithm;
+    }
+
+    /// Limits the size of packets and strings accepted from the server. Anything larger fails
+    /// the connection with `Error::PacketTooLarge` or `Error::StringTooLong`. Requests are sized
+    /// so that well-behaved servers stay within them.
+    pub fn set_decode_limits(&mut self, limits: DecodeLimits) {
+        self.sender.recv_state.lock().unwrap().decode_limits = limits;
+    }
+
+    pub fn decode_limits(&self) -> DecodeLimits {
+        self.sender.recv_state.lock().unwrap().decode_limits
+    }
+
+    /// A snapshot of this connection's request counts, data volume, errors and latencies since it
+    /// was opened, including everything done through files and directories opened from it.
+    pub fn stats(&self) -> Stats {
+        let state = self.sender.recv_state.lock().unwrap();
+        state.stats.snapshot(state.requests.len())
+    }
+
+    pub fn stat<P>(&mut self, path: P) -> Result<FileAttr> where P: AsRef<RemotePath> {
+        let p = packets::FxpStat{path: try!(self.sender.encode_path(path.as_ref()))};
         self.do_stat(p)
     }
 
-    pub fn lstat<S>(&mut self, path: S) -> Result<packets::FileAttr> where S: Into<String> {
-        let p = packets::FxpLStat{path: path.into().into()};
+    pub fn lstat<P>(&mut self, path: P) -> Result<FileAttr> where P: AsRef<RemotePath> {
+        let p = packets::FxpLStat{path: try!(self.sender.encode_path(path.as_ref()))};
         self.do_stat(p)
     }
 
+    pub fn metadata<P>(&mut self, path: P) -> Result<Metadata> where P: AsRef<RemotePath> {
+        Ok(Metadata::from(try!(self.stat(path))))
+    }
+
+    /// Like `metadata`, but does not follow symlinks.
+    pub fn symlink_metadata<P>(&mut self, path: P) -> Result<Metadata> where P: AsRef<RemotePath> {
+        Ok(Metadata::from(try!(self.lstat(path))))
+    }
+
     fn do_stat<T : packets::Request>(&mut self, p: T) -> Result<packets::FileAttr> {
         let resp = try!(self.sender.send_receive(&p));
         match resp {
@@ -161,31 +504,42 @@ impl<W> Client<W> where W : 'static + io::Write + Send {
         }
     }
 
-    pub fn setstat<S>(&mut self, path: S, attrs: packets::FileAttr) -> Result<()> where S: Into<String> {
-        let p = packets::FxpSetStat{path: path.into().into(), attrs: attrs};
+    pub fn setstat<P>(&mut self, path: P, attrs: FileAttr) -> Result<()> where P: AsRef<RemotePath> {
+        let p = packets::FxpSetStat{path: try!(self.sender.encode_path(path.as_ref())), attrs: attrs};
         let resp = try!(self.sender.send_receive(&p));
         Client::<W>::expect_status_response(resp)
     }
 
-    pub fn mkdir<S>(&mut self, path: S) -> Result<()> where S: Into<String> {
-        let p = packets::FxpMkDir{path: path.into().into(), attrs: packets::FileAttr::new()};
+    pub fn set_permissions<P>(&mut self, path: P, perms: Permissions) -> Result<()> where P: AsRef<RemotePath> {
+        let mut attrs = packets::FileAttr::new();
+        attrs.perms = Some(perms.mode());
+        self.setstat(path, attrs)
+    }
+
+    pub fn mkdir<P>(&mut self, path: P) -> Result<()> where P: AsRef<RemotePath> {
+        self.mkdir_with(path, packets::FileAttr::new())
+    }
+
+    /// Creates a directory with the given initial attributes, typically its permissions.
+    pub fn mkdir_with<P>(&mut self, path: P, attrs: FileAttr) -> Result<()> where P: AsRef<RemotePath> {
+        let p = packets::FxpMkDir{path: try!(self.sender.encode_path(path.as_ref())), attrs: attrs};
         let resp = try!(self.sender.send_receive(&p));
         Client::<W>::expect_status_response(resp)
     }
 
-    pub fn rmdir<S>(&mut self, path: S) -> Result<()> where S: Into<String> {
-        let p = packets::FxpRmDir{path: path.into().into()};
+    pub fn rmdir<P>(&mut self, path: P) -> Result<()> where P: AsRef<RemotePath> {
+        let p = packets::FxpRmDir{path: try!(self.sender.encode_path(path.as_ref()))};
         let resp = try!(self.sender.send_receive(&p));
         Client::<W>::expect_status_response(resp)
     }
 
-    pub fn realpath<S>(&mut self, path: S) -> Result<packets::Name> where S: Into<String> {
-        let p = packets::FxpRealPath{path: path.into().into()};
+    pub fn realpath<P>(&mut self, path: P) -> Result<RemotePathBuf> where P: AsRef<RemotePath> {
+        let p = packets::FxpRealPath{path: try!(self.sender.encode_path(path.as_ref()))};
         let resp = try!(self.sender.send_receive(&p));
         match resp {
             packets::SftpResponsePacket::Name(mut name) => {
                 if let Some(name) = name.names.pop() {
-                    Ok(name)
+                    self.sender.decode_path(name.filename)
                 } else {
                     Err(error::Error::UnexpectedResponse(Box::new(packets::SftpResponsePacket::Name(name))))
                 }
@@ -195,19 +549,22 @@ impl<W> Client<W> where W : 'static + io::Write + Send {
         }
     }
 
-    pub fn rename<S, T>(&mut self, oldpath: S, newpath: T) -> Result<()> where S: Into<String>, T: Into<String> {
-        let p = packets::FxpRename{oldpath: oldpath.into().into(), newpath: newpath.into().into()};
+    pub fn rename<P, Q>(&mut self, oldpath: P, newpath: Q) -> Result<()> where P: AsRef<RemotePath>, Q: AsRef<RemotePath> {
+        let p = packets::FxpRename{
+            oldpath: try!(self.sender.encode_path(oldpath.as_ref())),
+            newpath: try!(self.sender.encode_path(newpath.as_ref())),
+        };
         let resp = try!(self.sender.send_receive(&p));
         Client::<W>::expect_status_response(resp)
     }
 
-    pub fn readlink<S>(&mut self, path: S) -> Result<packets::Name> where S: Into<String> {
-        let p = packets::FxpReadLink{path: path.into().into()};
+    pub fn readlink<P>(&mut self, path: P) -> Result<RemotePathBuf> where P: AsRef<RemotePath> {
+        let p = packets::FxpReadLink{path: try!(self.sender.encode_path(path.as_ref()))};
         let resp = try!(self.sender.send_receive(&p));
         match resp {
             packets::SftpResponsePacket::Name(mut name) => {
                 if let Some(name) = name.names.pop() {
-                    Ok(name)
+                    self.sender.decode_path(name.filename)
                 } else {
                     Err(error::Error::UnexpectedResponse(Box::new(packets::SftpResponsePacket::Name(name))))
                 }
@@ -218,43 +575,196 @@ impl<W> Client<W> where W : 'static + io::Write + Send {
     }
 
     pub fn open_options(&mut self) -> OpenOptions<W> {
-        OpenOptions{client: self, flags: 0}
+        OpenOptions{client: self, flags: 0, attrs: packets::FileAttr::new()}
     }
 
-    fn open<S>(&mut self, filename: S, pflags: u32) -> Result<File<W>> where S: Into<String> {
-        let p = packets::FxpOpen{
-            filename: filename.into().into(),
-            pflags: pflags,
-            attrs: packets::FileAttr::new(),
+    pub fn dir_builder(&mut self) -> DirBuilder<W> {
+        DirBuilder::new(self)
+    }
+
+    fn open<P>(&mut self, filename: P, pflags: u32, attrs: packets::FileAttr) -> Result<File<W>> where P: AsRef<RemotePath> {
+        File::open(self.sender.clone(), try!(self.sender.encode_path(filename.as_ref())), pflags, attrs)
+    }
+
+    /// Copies a remote file to another remote path, replacing it if it exists. The data stays on
+    /// the server when it supports the copy-file or copy-data extensions; otherwise it is streamed
+    /// through the client.
+    pub fn copy<P, Q>(&mut self, src: P, dst: Q) -> Result<()> where P: AsRef<RemotePath>, Q: AsRef<RemotePath> {
+        let size = try!(self.stat(src.as_ref())).size;
+        let sender = self.sender.clone();
+        try!(sender.track(src.as_ref(), size, |tracker| self.copy_tracked(src.as_ref(), size, dst.as_ref(), tracker)));
+        self.verify_copy(src.as_ref(), 0, dst.as_ref(), 0, None)
+    }
+
+    // With `set_verify`, checks that `dst` from `dst_offset` matches `src` from `src_offset`, for
+    // `len` bytes or to the end of both.
+    fn verify_copy(&mut self, src: &RemotePath, src_offset: u64, dst: &RemotePath, dst_offset: u64,
+                   len: Option<u64>) -> Result<()> {
+        let algorithm = match *self.sender.verify.lock().unwrap() {
+            Some(algorithm) => algorithm,
+            None => return Ok(()),
         };
-        let resp = try!(self.sender.send_receive(&p));
-        match resp {
-            packets::SftpResponsePacket::Handle(handle) => {
-                Ok(File{client: self.sender.clone(), handle: handle.handle, offset: 0})
-            },
-            packets::SftpResponsePacket::Status(status) => Err(error::Error::FromServer(Box::new(status))),
-            x => Err(error::Error::UnexpectedResponse(Box::new(x))),
+        let expected = try!(self.checksum(src, algorithm, len.map(|len| src_offset..src_offset + len)));
+        checksum::verify_path(&self.sender, dst, algorithm, len.map(|len| dst_offset..dst_offset + len), &expected)
+    }
+
+    // With `set_verify`, checks that `path`, or a range of it, holds `contents`.
+    fn verify_contents(&self, path: &RemotePath, range: Option<Range<u64>>, contents: &[u8]) -> Result<()> {
+        let algorithm = match *self.sender.verify.lock().unwrap() {
+            Some(algorithm) => algorithm,
+            None => return Ok(()),
+        };
+        let expected = try!(algorithm.digest(&mut &contents[..]));
+        checksum::verify_path(&self.sender, path, algorithm, range, &expected)
+    }
+
+    fn copy_tracked(&mut self, src: &RemotePath, size: Option<u64>, dst: &RemotePath, tracker: Option<&Tracker>) -> Result<()> {
+        let src = try!(self.sender.encode_path(src));
+        let dst = try!(self.sender.encode_path(dst));
+        if self.sender.has_extension(packets::COPY_FILE) {
+            let p = packets::FxpCopyFile{
+                source: src.clone(),
+                destination: dst.clone(),
+                overwrite: true,
+            };
+            match try!(self.sender.send_receive(&p)) {
+                packets::SftpResponsePacket::Status(packets::FxpStatus{code:
+                    packets::FxpStatusCode::OpUnsupported, msg: _}) => {},
+                resp => {
+                    try!(Client::<W>::expect_status_response(resp));
+                    if let (Some(tracker), Some(size)) = (tracker, size) {
+                        tracker.add(size);
+                    }
+                    return Ok(());
+                },
+            }
         }
+        let src_file = try!(File::open(self.sender.clone(), src, SSH_FXF_READ, packets::FileAttr::new()));
+        let dst_file = try!(File::open(self.sender.clone(), dst, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC, packets::FileAttr::new()));
+        try!(transfer::copy_data(&src_file, 0, 0, size, &dst_file, 0, tracker));
+        Ok(())
+    }
+
+    /// Copies `len` bytes starting at `src_offset` of `src` into `dst` at `dst_offset`. A `len` of
+    /// zero copies until the end of `src`. `dst` is created if needed but not truncated.
+    ///
+    /// Returns the number of bytes copied, like `std::io::copy`. This is less than `len` when
+    /// `src` ends before the range does.
+    pub fn copy_range<P, Q>(&mut self, src: P, src_offset: u64, len: u64, dst: Q, dst_offset: u64) -> Result<u64>
+        where P: AsRef<RemotePath>, Q: AsRef<RemotePath> {
+        let src = src.as_ref();
+        let mut src_file = try!(File::open(self.sender.clone(), try!(self.sender.encode_path(src)), SSH_FXF_READ,
+                                           packets::FileAttr::new()));
+        let size = try!(src_file.stat()).size;
+        let copied = try!(self.sender.track(src, transfer::copy_len(size, src_offset, len), |tracker| {
+            let dst_file = try!(File::open(self.sender.clone(), try!(self.sender.encode_path(dst.as_ref())),
+                                           SSH_FXF_WRITE | SSH_FXF_CREAT, packets::FileAttr::new()));
+            transfer::copy_data(&src_file, src_offset, len, size, &dst_file, dst_offset, tracker)
+        }));
+        try!(self.verify_copy(src, src_offset, dst.as_ref(), dst_offset, Some(copied)));
+        Ok(copied)
+    }
+
+    /// Reads the whole file, like `std::fs::read`.
+    pub fn read<P>(&mut self, path: P) -> Result<Vec<u8>> where P: AsRef<RemotePath> {
+        let mut file = try!(self.open(path.as_ref(), SSH_FXF_READ, packets::FileAttr::new()));
+        let size = try!(file.stat()).size;
+        let contents = try!(self.sender.track(path.as_ref(), size, |tracker| transfer::read_to_end(&file, 0, size, tracker)));
+        try!(self.verify_contents(path.as_ref(), None, &contents));
+        Ok(contents)
+    }
+
+    pub fn read_to_string<P>(&mut self, path: P) -> Result<String> where P: AsRef<RemotePath> {
+        Ok(try!(String::from_utf8(try!(self.read(path)))))
+    }
+
+    /// Replaces the contents of the file, creating it if needed, like `std::fs::write`.
+    pub fn write<P, C>(&mut self, path: P, contents: C) -> Result<()> where P: AsRef<RemotePath>, C: AsRef<[u8]> {
+        let contents = contents.as_ref();
+        let file = try!(self.open(path.as_ref(), SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_TRUNC, packets::FileAttr::new()));
+        try!(self.sender.track(path.as_ref(), Some(contents.len() as u64),
+                               |tracker| transfer::write_all_at(&file, 0, contents, tracker)));
+        drop(file);
+        self.verify_contents(path.as_ref(), None, contents)
+    }
+
+    /// Replaces the file without ever exposing partial contents. See `AtomicWrite`.
+    pub fn write_atomic<P, C>(&mut self, path: P, contents: C) -> Result<()> where P: AsRef<RemotePath>, C: AsRef<[u8]> {
+        self.atomic_write().write(path, contents)
+    }
+
+    pub fn atomic_write(&mut self) -> AtomicWrite<W> {
+        AtomicWrite::new(self)
     }
 
-    pub fn remove<S>(&mut self, filename: S) -> Result<()> where S: Into<String> {
-        let p = packets::FxpRemove{filename: filename.into().into()};
+    /// Adds to the end of the file, creating it if needed.
+    pub fn append<P, C>(&mut self, path: P, contents: C) -> Result<()> where P: AsRef<RemotePath>, C: AsRef<[u8]> {
+        let contents = contents.as_ref();
+        let mut file = try!(self.open(path.as_ref(), SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_APPEND, packets::FileAttr::new()));
+        // Not every server honours the append flag, so the writes also carry the right offsets.
+        let size = try!(file.stat()).size.unwrap_or(0);
+        try!(self.sender.track(path.as_ref(), Some(contents.len() as u64),
+                               |tracker| transfer::write_all_at(&file, size, contents, tracker)));
+        drop(file);
+        self.verify_contents(path.as_ref(), Some(size..size + contents.len() as u64), contents)
+    }
+
+    /// Computes a hash of `path`, or of a byte range of it. The server computes it when it
+    /// supports check-file-name or md5-hash; otherwise the data is read back and hashed locally.
+    pub fn checksum<P>(&mut self, path: P, algorithm: HashAlgorithm, range: Option<Range<u64>>) -> Result<Vec<u8>>
+        where P: AsRef<RemotePath> {
+        checksum::checksum_path(&self.sender, try!(self.sender.encode_path(path.as_ref())), algorithm, range)
+    }
+
+    pub fn segmented_download(&mut self) -> SegmentedDownload<W> {
+        SegmentedDownload::new(self)
+    }
+
+    pub fn remove<P>(&mut self, filename: P) -> Result<()> where P: AsRef<RemotePath> {
+        let p = packets::FxpRemove{filename: try!(self.sender.encode_path(filename.as_ref()))};
         let resp = try!(self.sender.send_receive(&p));
         Client::<W>::expect_status_response(resp)
     }
 
-    pub fn readdir<S>(&mut self, path: S) -> Result<ReadDir<W>> where S: Into<String> {
-        let p = packets::FxpOpenDir{path: path.into().into()};
+    pub fn readdir<P>(&mut self, path: P) -> Result<ReadDir<W>> where P: AsRef<RemotePath> {
+        let p = packets::FxpOpenDir{path: try!(self.sender.encode_path(path.as_ref()))};
         let resp = try!(self.sender.send_receive(&p));
         match resp {
             packets::SftpResponsePacket::Handle(handle) => {
-                Ok(ReadDir{client: self.sender.clone(), handle: handle.handle, names: Vec::new().into_iter()})
+                Ok(ReadDir{
+                    client: self.sender.clone(),
+                    handle: handle.handle,
+                    dir: path.as_ref().to_remote_path_buf(),
+                    names: Vec::new().into_iter(),
+                    skip_dots: false,
+                })
             },
             packets::SftpResponsePacket::Status(status) => Err(error::Error::FromServer(Box::new(status))),
             x => Err(error::Error::UnexpectedResponse(Box::new(x))),
         }
     }
 
+    /// Creates a directory and any missing parents, like `mkdir -p`. Directories that already
+    /// exist, including ones created concurrently by someone else, are not an error.
+    pub fn create_dir_all<P>(&mut self, path: P, mode: u32) -> Result<()> where P: AsRef<RemotePath> {
+        let mut attrs = packets::FileAttr::new();
+        attrs.perms = Some(mode);
+        dir::create_dir_all(self, path.as_ref(), &attrs)
+    }
+
+    /// Removes a directory and everything below it, like `rm -rf`. Symlinks are removed rather
+    /// than followed. Errors are wrapped in `Error::AtPath` naming the entry that failed.
+    pub fn remove_dir_all<P>(&mut self, path: P) -> Result<()> where P: AsRef<RemotePath> {
+        dir::remove_dir_all(self, path.as_ref())
+    }
+
+    /// Reads a whole directory at once, filling in attributes the server left out of the listing
+    /// with concurrent stat requests, and sorts the result.
+    pub fn list_dir<P>(&mut self, path: P, options: &ListOptions) -> Result<Vec<DirEntry>> where P: AsRef<RemotePath> {
+        let read_dir = try!(self.readdir(path));
+        dir::list_dir(&self.sender, read_dir, options)
+    }
+
     fn expect_status_response(resp : packets::SftpResponsePacket) -> Result<()> {
         match resp {
             packets::SftpResponsePacket::Status(packets::FxpStatus{code:
@@ -265,16 +775,10 @@ impl<W> Client<W> where W : 'static + io::Write + Send {
     }
 }
 
-const SSH_FXF_READ : u32 = 0x00000001;
-const SSH_FXF_WRITE : u32 = 0x00000002;
-const SSH_FXF_APPEND : u32 = 0x00000004;
-const SSH_FXF_CREAT : u32 = 0x00000008;
-const SSH_FXF_TRUNC : u32 = 0x00000010;
-const SSH_FXF_EXCL : u32 = 0x00000020;
-
 pub struct OpenOptions<'a, W> where W: 'a {
     client: &'a mut Client<W>,
     flags: u32,
+    attrs: packets::FileAttr,
 }
 
 impl<'a, W> OpenOptions<'a, W> where W : 'static + io::Write + Send {
@@ -311,8 +815,20 @@ impl<'a, W> OpenOptions<'a, W> where W : 'static + io::Write + Send {
         self.flag(SSH_FXF_EXCL, exclude)
     }
 
-    pub fn open<S>(&mut self, path: S) -> Result<File<W>> where S: Into<String> {
-        self.client.open(path, self.flags)
+    /// The permissions of the file if it is created. Existing files are left alone.
+    pub fn mode(&mut self, mode: u32) -> &mut OpenOptions<'a, W> {
+        self.attrs.perms = Some(mode);
+        self
+    }
+
+    /// The attributes of the file if it is created, replacing any previously set mode.
+    pub fn attrs(&mut self, attrs: FileAttr) -> &mut OpenOptions<'a, W> {
+        self.attrs = attrs;
+        self
+    }
+
+    pub fn open<P>(&mut self, path: P) -> Result<File<W>> where P: AsRef<RemotePath> {
+        self.client.open(path, self.flags, self.attrs.clone())
     }
 }
 
@@ -320,10 +836,27 @@ pub struct File<W> where W : 'static + io::Write + Send {
     client: Arc<ClientSender<W>>,
     handle: Vec<u8>,
     offset: u64,
+    closed: bool,
 }
 
 impl<W> File<W>  where W : 'static + io::Write + Send {
-    pub fn stat(&mut self) -> Result<packets::FileAttr> {
+    fn open(client: Arc<ClientSender<W>>, filename: Vec<u8>, pflags: u32, attrs: packets::FileAttr) -> Result<File<W>> {
+        let p = packets::FxpOpen{
+            filename: filename,
+            pflags: pflags,
+            attrs: attrs,
+        };
+        let resp = try!(client.send_receive(&p));
+        match resp {
+            packets::SftpResponsePacket::Handle(handle) => {
+                Ok(File{client: client, handle: handle.handle, offset: 0, closed: false})
+            },
+            packets::SftpResponsePacket::Status(status) => Err(error::Error::FromServer(Box::new(status))),
+            x => Err(error::Error::UnexpectedResponse(Box::new(x))),
+        }
+    }
+
+    pub fn stat(&mut self) -> Result<FileAttr> {
         let p = packets::FxpFStat{handle: self.handle.clone()};
         let resp = try!(self.client.send_receive(&p));
         match resp {
@@ -334,55 +867,98 @@ impl<W> File<W>  where W : 'static + io::Write + Send {
         }
     }
 
-    pub fn setstat(&mut self, attrs: packets::FileAttr) -> Result<()> {
+    pub fn setstat(&mut self, attrs: FileAttr) -> Result<()> {
         let p = packets::FxpFSetStat{handle: self.handle.clone(), attrs: attrs};
         let resp = try!(self.client.send_receive(&p));
         Client::<W>::expect_status_response(resp)
     }
+
+    pub fn metadata(&mut self) -> Result<Metadata> {
+        Ok(Metadata::from(try!(self.stat())))
+    }
+
+    pub fn checksum(&self, algorithm: HashAlgorithm, range: Option<Range<u64>>) -> Result<Vec<u8>> {
+        checksum::checksum_handle(self, algorithm, range)
+    }
+
+    /// Reads into `buf` from `offset` without using or moving the cursor, so a shared `File` can
+    /// be read from several threads at once. Returns 0 at the end of the file. At most the
+    /// server's read limit is read at once.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let len = cmp::min(buf.len(), self.client.max_read_len());
+        let p = packets::FxpRead{handle: self.handle.clone(),
+                                 offset: offset,
+                                 len: len as u32};
+        let resp = match try!(self.client.send_receive_into(&p, &mut buf[..len])) {
+            packets::Received::DataInBuffer(n) => return Ok(n),
+            packets::Received::Packet(resp) => resp,
+        };
+        match resp {
+            packets::SftpResponsePacket::Status(packets::FxpStatus{code: packets::FxpStatusCode::EOF, msg: _}) => Ok(0),
+            packets::SftpResponsePacket::Status(status) => Err(error::Error::FromServer(Box::new(status))),
+            x => Err(error::Error::UnexpectedResponse(Box::new(x))),
+        }
+    }
+
+    /// Writes `buf` at `offset` without using or moving the cursor. Sends one request, so at
+    /// most the server's write limit is written; returns how much that was.
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        let len = cmp::min(buf.len(), self.client.max_write_len());
+        let p = packets::FxpWrite{handle: Cow::Borrowed(&self.handle),
+                                  offset: offset,
+                                  data: Cow::Borrowed(&buf[..len])};
+        let resp = try!(self.client.send_receive(&p));
+        try!(Client::<W>::expect_status_response(resp));
+        Ok(len)
+    }
+
+    /// Closes the handle. Unlike dropping the file, this reports errors, which for some servers
+    /// is the only sign that buffered data could not be written.
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        let p = packets::FxpClose{handle: self.handle.clone()};
+        let resp = try!(self.client.send_receive(&p));
+        Client::<W>::expect_status_response(resp)
+    }
+
+    /// Like `write_at`, but splits large buffers into several requests that are in flight at
+    /// the same time.
+    pub fn write_all_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        transfer::write_all_at(self, offset, buf, None)
+    }
 }
 
 impl<W> Drop for File<W> where W : 'static + io::Write + Send {
     fn drop(&mut self) {
-        let p = packets::FxpClose{handle: self.handle.clone()};
-        let _ = self.client.send_receive(&p);
+        if !self.closed {
+            let p = packets::FxpClose{handle: self.handle.clone()};
+            let _ = self.client.send_receive(&p);
+        }
     }
 }
 
 impl<W> io::Read for File<W> where W : 'static + io::Write + Send {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let p = packets::FxpRead{handle: self.handle.clone(),
-                                 offset: self.offset,
-                                 len: buf.len() as u32};
-        let resp = match self.client.send_receive(&p) {
-            Ok(data) => data,
-            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "unknown error")),
-        };
-        match resp {
-            packets::SftpResponsePacket::Data(mut data) => {
-                let n = buf.clone_from_slice(&mut data.data[..]);
+        let offset = self.offset;
+        match self.read_at(buf, offset) {
+            Ok(n) => {
                 self.offset += n as u64;
                 Ok(n)
             },
-            packets::SftpResponsePacket::Status(packets::FxpStatus{code: packets::FxpStatusCode::EOF, msg: _}) => Ok(0),
-            packets::SftpResponsePacket::Status(status) => Err(From::from(status)),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "unknown error")),
+            Err(e) => Err(From::from(e)),
         }
     }
 }
 
 impl<W> io::Write for File<W> where W : 'static + io::Write + Send {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let p = packets::FxpWrite{handle: self.handle.clone(),
-                                  offset: self.offset,
-                                  data: buf.into()};
-        let resp = match self.client.send_receive(&p) {
-            Ok(data) => data,
-            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "unknown error")),
-        };
-        match resp {
-            packets::SftpResponsePacket::Status(packets::FxpStatus{code: packets::FxpStatusCode::Ok, msg: _}) => { self.offset += p.data.len() as u64; Ok(p.data.len()) },
-            packets::SftpResponsePacket::Status(status) => Err(From::from(status)),
-            _ => Err(io::Error::new(io::ErrorKind::Other, "unknown error")),
+        let offset = self.offset;
+        match self.write_at(buf, offset) {
+            Ok(n) => {
+                self.offset += n as u64;
+                Ok(n)
+            },
+            Err(e) => Err(From::from(e)),
         }
     }
 
@@ -421,7 +997,17 @@ impl<W> io::Seek for File<W> where W : 'static + io::Write + Send {
 pub struct ReadDir<W> where W : 'static + io::Write + Send {
     client: Arc<ClientSender<W>>,
     handle: Vec<u8>,
+    dir: RemotePathBuf,
     names: std::vec::IntoIter<packets::Name>,
+    skip_dots: bool,
+}
+
+impl<W> ReadDir<W> where W : 'static + io::Write + Send {
+    /// Leaves out the `.` and `..` entries most servers return.
+    pub fn skip_dots(&mut self, skip_dots: bool) -> &mut ReadDir<W> {
+        self.skip_dots = skip_dots;
+        self
+    }
 }
 
 impl<W> Drop for ReadDir<W> where W : 'static + io::Write + Send {
@@ -432,11 +1018,22 @@ impl<W> Drop for ReadDir<W> where W : 'static + io::Write + Send {
 }
 
 impl<W> Iterator for ReadDir<W> where W : 'static + io::Write + Send {
-    type Item = Result<packets::Name>;
+    type Item = Result<DirEntry>;
 
-    fn next(&mut self) -> Option<Result<packets::Name>> {
+    fn next(&mut self) -> Option<Result<DirEntry>> {
         match self.names.next() {
-            Some(name) => Some(Ok(name)),
+            Some(name) => {
+                let filename = match self.client.decode_path(name.filename) {
+                    Ok(filename) => filename,
+                    Err(e) => return Some(Err(e)),
+                };
+                let longname = self.client.encoding.lock().unwrap().decode_lossy(name.longname);
+                let entry = DirEntry::new(&self.dir, filename, longname, name.attrs);
+                if self.skip_dots && entry.is_dot_or_dot_dot() {
+                    return self.next();
+                }
+                Some(Ok(entry))
+            },
             None => {
                 let p = packets::FxpReadDir{handle: self.handle.clone()};
                 let resp = match self.client.send_receive(&p) {
@@ -0,0 +1,22 @@
+// Stand-ins for the logging macros when neither the `log` nor the `tracing` feature is on. The
+// arguments are still type-checked, but the code is never run and optimizes away.
+
+macro_rules! error {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { if false { let _ = format!($($arg)*); } }
+}
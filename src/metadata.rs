@@ -0,0 +1,171 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use packets::FileAttr;
+
+const S_IFMT : u32 = 0o170000;
+const S_IFDIR : u32 = 0o040000;
+const S_IFREG : u32 = 0o100000;
+const S_IFLNK : u32 = 0o120000;
+
+/// Metadata about a remote file, modeled after `std::fs::Metadata`.
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    attrs: FileAttr,
+}
+
+impl Metadata {
+    pub fn attrs(&self) -> &FileAttr {
+        &self.attrs
+    }
+
+    pub fn into_attrs(self) -> FileAttr {
+        self.attrs
+    }
+
+    pub fn file_type(&self) -> FileType {
+        FileType{mode: self.attrs.perms.unwrap_or(0)}
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type().is_dir()
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type().is_file()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type().is_symlink()
+    }
+
+    /// The size in bytes, or zero if the server did not report it.
+    pub fn len(&self) -> u64 {
+        self.attrs.size.unwrap_or(0)
+    }
+
+    pub fn uid(&self) -> Option<u32> {
+        self.attrs.uid
+    }
+
+    pub fn gid(&self) -> Option<u32> {
+        self.attrs.gid
+    }
+
+    pub fn modified(&self) -> io::Result<SystemTime> {
+        to_system_time(self.attrs.mtime, "modification time not available")
+    }
+
+    pub fn accessed(&self) -> io::Result<SystemTime> {
+        to_system_time(self.attrs.atime, "access time not available")
+    }
+
+    pub fn permissions(&self) -> Permissions {
+        Permissions{mode: self.attrs.perms.unwrap_or(0) & !S_IFMT}
+    }
+}
+
+fn to_system_time(secs: Option<u32>, missing: &'static str) -> io::Result<SystemTime> {
+    match secs {
+        Some(secs) => Ok(UNIX_EPOCH + Duration::from_secs(secs as u64)),
+        None => Err(io::Error::new(io::ErrorKind::Other, missing)),
+    }
+}
+
+impl From<FileAttr> for Metadata {
+    fn from(attrs: FileAttr) -> Metadata {
+        Metadata{attrs: attrs}
+    }
+}
+
+impl From<Metadata> for FileAttr {
+    fn from(metadata: Metadata) -> FileAttr {
+        metadata.attrs
+    }
+}
+
+// There is no way to construct a `std::fs::Metadata`, so the conversion only goes this way. The
+// result can be turned into a `FileAttr` to replicate a local file's attributes remotely.
+#[cfg(unix)]
+impl From<fs::Metadata> for Metadata {
+    fn from(metadata: fs::Metadata) -> Metadata {
+        use std::os::unix::fs::MetadataExt;
+        let mut attrs = FileAttr::new();
+        attrs.size = Some(metadata.size());
+        attrs.uid = Some(metadata.uid());
+        attrs.gid = Some(metadata.gid());
+        attrs.perms = Some(metadata.mode());
+        attrs.atime = Some(metadata.atime() as u32);
+        attrs.mtime = Some(metadata.mtime() as u32);
+        Metadata{attrs: attrs}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileType {
+    mode: u32,
+}
+
+impl FileType {
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+/// Unix permission bits of a remote file, modeled after `std::fs::Permissions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    mode: u32,
+}
+
+impl Permissions {
+    pub fn from_mode(mode: u32) -> Permissions {
+        Permissions{mode: mode & !S_IFMT}
+    }
+
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: u32) {
+        self.mode = mode & !S_IFMT;
+    }
+
+    /// True when nobody has write permission.
+    pub fn readonly(&self) -> bool {
+        self.mode & 0o222 == 0
+    }
+
+    pub fn set_readonly(&mut self, readonly: bool) {
+        if readonly {
+            self.mode &= !0o222;
+        } else {
+            self.mode |= 0o222;
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<fs::Permissions> for Permissions {
+    fn from(perms: fs::Permissions) -> Permissions {
+        use std::os::unix::fs::PermissionsExt;
+        Permissions::from_mode(perms.mode())
+    }
+}
+
+#[cfg(unix)]
+impl From<Permissions> for fs::Permissions {
+    fn from(perms: Permissions) -> fs::Permissions {
+        use std::os::unix::fs::PermissionsExt;
+        fs::Permissions::from_mode(perms.mode)
+    }
+}
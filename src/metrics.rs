@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::time::Duration;
+
+use error::Result;
+use packets;
+
+/// Upper bounds, in seconds, of the latency histogram buckets. Slower requests land in one more
+/// bucket past the last bound.
+pub const LATENCY_BUCKETS : &'static [f64] = &[0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+                                              2.5, 5.0, 10.0];
+
+/// Distribution of request latencies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Requests per bucket of `LATENCY_BUCKETS`, not cumulative, followed by the overflow bucket.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    /// Total latency in seconds.
+    pub sum: f64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram{buckets: vec![0; LATENCY_BUCKETS.len() + 1], count: 0, sum: 0.0}
+    }
+
+    fn observe(&mut self, secs: f64) {
+        let i = LATENCY_BUCKETS.iter().position(|&b| secs <= b).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i] += 1;
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.count > 0 { Some(self.sum / self.count as f64) } else { None }
+    }
+
+    /// An upper bound for the `q` quantile (0 to 1): the bound of the bucket it falls into, or
+    /// infinity for the overflow bucket.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(LATENCY_BUCKETS.get(i).cloned().unwrap_or(::std::f64::INFINITY));
+            }
+        }
+        Some(::std::f64::INFINITY)
+    }
+}
+
+/// Statistics of one connection, returned by `Client::stats`. Requests are keyed by the name of
+/// the message type, e.g. `open` or `read`, or by the extension name for extended requests.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Requests written out completely, including SSH_FXP_INIT and the limits@openssh.com query
+    /// made while connecting.
+    pub requests: BTreeMap<&'static str, u64>,
+    /// Requests still waiting for a response.
+    pub outstanding: usize,
+    /// File data received in SSH_FXP_DATA responses.
+    pub bytes_read: u64,
+    /// File data sent in SSH_FXP_WRITE requests.
+    pub bytes_written: u64,
+    /// Status responses other than SSH_FX_OK, by `FxpStatusCode::name`. End of file is counted
+    /// as well, so that the numbers add up; it is usually not an error.
+    pub errors: BTreeMap<&'static str, u64>,
+    /// Time from sending a request to receiving its response.
+    pub latency: BTreeMap<&'static str, Histogram>,
+}
+
+// Collects the statistics as requests go out and responses come in.
+pub struct Recorder {
+    stats: Stats,
+}
+
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder{stats: Stats::default()}
+    }
+
+    pub fn request_sent(&mut self, name: &'static str, msg_type: u8, payload_len: u64) {
+        *self.stats.requests.entry(name).or_insert(0) += 1;
+        if msg_type == packets::SSH_FXP_WRITE {
+            self.stats.bytes_written += payload_len;
+        }
+    }
+
+    pub fn response_received(&mut self, name: &'static str, latency: Duration, resp: &packets::SftpResponsePacket) {
+        self.observe(name, latency);
+        match *resp {
+            packets::SftpResponsePacket::Data(ref data) => self.stats.bytes_read += data.data.len() as u64,
+            packets::SftpResponsePacket::Status(ref status) if status.code != packets::FxpStatusCode::Ok =>
+                *self.stats.errors.entry(status.code.name()).or_insert(0) += 1,
+            _ => {},
+        }
+    }
+
+    // SSH_FXP_DATA that was decoded straight into the requester's buffer.
+    pub fn data_received(&mut self, name: &'static str, latency: Duration, len: usize) {
+        self.observe(name, latency);
+        self.stats.bytes_read += len as u64;
+    }
+
+    fn observe(&mut self, name: &'static str, latency: Duration) {
+        let secs = latency.as_secs() as f64 + latency.subsec_nanos() as f64 / 1e9;
+        self.stats.latency.entry(name).or_insert_with(Histogram::new).observe(secs);
+    }
+
+    pub fn snapshot(&self, outstanding: usize) -> Stats {
+        let mut stats = self.stats.clone();
+        stats.outstanding = outstanding;
+        stats
+    }
+}
+
+/// Somewhere to publish `Stats`, e.g. periodically from a background thread.
+pub trait StatsSink {
+    fn export(&mut self, stats: &Stats) -> Result<()>;
+}
+
+/// Writes `Stats` in the Prometheus text exposition format. Labels added with `label`, e.g. the
+/// host name, are attached to every sample so several connections can share one scrape.
+pub struct PrometheusSink<W> {
+    w: W,
+    labels: Vec<(String, String)>,
+}
+
+impl<W> PrometheusSink<W> where W: io::Write {
+    pub fn new(w: W) -> PrometheusSink<W> {
+        PrometheusSink{w: w, labels: Vec::new()}
+    }
+
+    pub fn label<N, V>(&mut self, name: N, value: V) -> &mut PrometheusSink<W> where N: Into<String>, V: Into<String> {
+        self.labels.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
+    // The configured labels plus `extra`, formatted as `{a="b",...}`, or nothing if there are none.
+    fn labels(&self, extra: &[(&str, &str)]) -> String {
+        let all: Vec<String> = self.labels.iter().map(|&(ref n, ref v)| (n.as_str(), v.as_str()))
+            .chain(extra.iter().cloned())
+            .map(|(n, v)| format!("{}=\"{}\"", n, escape_label(v)))
+            .collect();
+        if all.is_empty() { String::new() } else { format!("{{{}}}", all.join(",")) }
+    }
+}
+
+impl<W> StatsSink for PrometheusSink<W> where W: io::Write {
+    fn export(&mut self, stats: &Stats) -> Result<()> {
+        let mut out = String::new();
+        out.push_str("# HELP sftp_requests_total Requests sent to the server.\n");
+        out.push_str("# TYPE sftp_requests_total counter\n");
+        for (op, n) in stats.requests.iter() {
+            out.push_str(&format!("sftp_requests_total{} {}\n", self.labels(&[("op", op)]), n));
+        }
+        out.push_str("# HELP sftp_outstanding_requests Requests waiting for a response.\n");
+        out.push_str("# TYPE sftp_outstanding_requests gauge\n");
+        out.push_str(&format!("sftp_outstanding_requests{} {}\n", self.labels(&[]), stats.outstanding));
+        out.push_str("# HELP sftp_read_bytes_total File data read from the server.\n");
+        out.push_str("# TYPE sftp_read_bytes_total counter\n");
+        out.push_str(&format!("sftp_read_bytes_total{} {}\n", self.labels(&[]), stats.bytes_read));
+        out.push_str("# HELP sftp_written_bytes_total File data written to the server.\n");
+        out.push_str("# TYPE sftp_written_bytes_total counter\n");
+        out.push_str(&format!("sftp_written_bytes_total{} {}\n", self.labels(&[]), stats.bytes_written));
+        out.push_str("# HELP sftp_status_errors_total Status responses other than ok.\n");
+        out.push_str("# TYPE sftp_status_errors_total counter\n");
+        for (code, n) in stats.errors.iter() {
+            out.push_str(&format!("sftp_status_errors_total{} {}\n", self.labels(&[("code", code)]), n));
+        }
+        out.push_str("# HELP sftp_request_duration_seconds Time until the server responded.\n");
+        out.push_str("# TYPE sftp_request_duration_seconds histogram\n");
+        for (op, h) in stats.latency.iter() {
+            let mut cumulative = 0;
+            for (i, n) in h.buckets.iter().enumerate() {
+                cumulative += *n;
+                let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |b| b.to_string());
+                out.push_str(&format!("sftp_request_duration_seconds_bucket{} {}\n",
+                                      self.labels(&[("op", op), ("le", &le)]), cumulative));
+            }
+            out.push_str(&format!("sftp_request_duration_seconds_sum{} {}\n", self.labels(&[("op", op)]), h.sum));
+            out.push_str(&format!("sftp_request_duration_seconds_count{} {}\n", self.labels(&[("op", op)]), h.count));
+        }
+        Ok(try!(self.w.write_all(out.as_bytes())))
+    }
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
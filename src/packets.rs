@@ -2,6 +2,7 @@ extern crate byteorder;
 
 use self::byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
+use std::borrow::Cow;
 use std::fmt;
 use std::io;
 use std::io::Read;
@@ -9,65 +10,200 @@ use std::error::Error as StdError;
 use error::{Error, Result};
 
 // Init
-const SSH_FXP_INIT : u8 = 1;
-const SSH_FXP_VERSION : u8 = 2;
+pub const SSH_FXP_INIT : u8 = 1;
+pub const SSH_FXP_VERSION : u8 = 2;
 
 // Requests
-const SSH_FXP_OPEN : u8 = 3;
-const SSH_FXP_CLOSE : u8 = 4;
-const SSH_FXP_READ : u8 = 5;
-const SSH_FXP_WRITE : u8 = 6;
-const SSH_FXP_LSTAT : u8 = 7;
-const SSH_FXP_FSTAT : u8 = 8;
-const SSH_FXP_SETSTAT : u8 = 9;
-const SSH_FXP_FSETSTAT : u8 = 10;
-const SSH_FXP_OPENDIR : u8 = 11;
-const SSH_FXP_READDIR : u8 = 12;
-const SSH_FXP_REMOVE : u8 = 13;
-const SSH_FXP_MKDIR : u8 = 14;
-const SSH_FXP_RMDIR : u8 = 15;
-const SSH_FXP_REALPATH : u8 = 16;
-const SSH_FXP_STAT : u8 = 17;
-const SSH_FXP_RENAME : u8 = 18;
-const SSH_FXP_READLINK : u8 = 19;
+pub const SSH_FXP_OPEN : u8 = 3;
+pub const SSH_FXP_CLOSE : u8 = 4;
+pub const SSH_FXP_READ : u8 = 5;
+pub const SSH_FXP_WRITE : u8 = 6;
+pub const SSH_FXP_LSTAT : u8 = 7;
+pub const SSH_FXP_FSTAT : u8 = 8;
+pub const SSH_FXP_SETSTAT : u8 = 9;
+pub const SSH_FXP_FSETSTAT : u8 = 10;
+pub const SSH_FXP_OPENDIR : u8 = 11;
+pub const SSH_FXP_READDIR : u8 = 12;
+pub const SSH_FXP_REMOVE : u8 = 13;
+pub const SSH_FXP_MKDIR : u8 = 14;
+pub const SSH_FXP_RMDIR : u8 = 15;
+pub const SSH_FXP_REALPATH : u8 = 16;
+pub const SSH_FXP_STAT : u8 = 17;
+pub const SSH_FXP_RENAME : u8 = 18;
+pub const SSH_FXP_READLINK : u8 = 19;
 // SSH_FXP_SYMLINK is not implemented because openssh sftp server reversed the order of the
 // arguments, making it incompatible with the rfc and other implementations.
 //const SSH_FXP_SYMLINK : u8 = 20;
 
 // Responses
-const SSH_FXP_STATUS : u8 = 101;
-const SSH_FXP_HANDLE : u8 = 102;
-const SSH_FXP_DATA : u8 = 103;
-const SSH_FXP_NAME : u8 = 104;
-const SSH_FXP_ATTRS : u8 = 105;
-//const SSH_FXP_EXTENDED : u8 = 200;
-//const SSH_FXP_EXTENDED_REPLY : u8 = 201;
-
+pub const SSH_FXP_STATUS : u8 = 101;
+pub const SSH_FXP_HANDLE : u8 = 102;
+pub const SSH_FXP_DATA : u8 = 103;
+pub const SSH_FXP_NAME : u8 = 104;
+pub const SSH_FXP_ATTRS : u8 = 105;
+pub const SSH_FXP_EXTENDED : u8 = 200;
+pub const SSH_FXP_EXTENDED_REPLY : u8 = 201;
+
+/// The name of a message type for logs and statistics, e.g. `open` for SSH_FXP_OPEN.
+pub fn msg_type_name(msg_type: u8) -> &'static str {
+    match msg_type {
+        SSH_FXP_INIT => "init",
+        SSH_FXP_VERSION => "version",
+        SSH_FXP_OPEN => "open",
+        SSH_FXP_CLOSE => "close",
+        SSH_FXP_READ => "read",
+        SSH_FXP_WRITE => "write",
+        SSH_FXP_LSTAT => "lstat",
+        SSH_FXP_FSTAT => "fstat",
+        SSH_FXP_SETSTAT => "setstat",
+        SSH_FXP_FSETSTAT => "fsetstat",
+        SSH_FXP_OPENDIR => "opendir",
+        SSH_FXP_READDIR => "readdir",
+        SSH_FXP_REMOVE => "remove",
+        SSH_FXP_MKDIR => "mkdir",
+        SSH_FXP_RMDIR => "rmdir",
+        SSH_FXP_REALPATH => "realpath",
+        SSH_FXP_STAT => "stat",
+        SSH_FXP_RENAME => "rename",
+        SSH_FXP_READLINK => "readlink",
+        SSH_FXP_STATUS => "status",
+        SSH_FXP_HANDLE => "handle",
+        SSH_FXP_DATA => "data",
+        SSH_FXP_NAME => "name",
+        SSH_FXP_ATTRS => "attrs",
+        SSH_FXP_EXTENDED => "extended",
+        SSH_FXP_EXTENDED_REPLY => "extended-reply",
+        _ => "unknown",
+    }
+}
+
+// Extensions
+pub const COPY_DATA : &'static str = "copy-data";
+pub const COPY_FILE : &'static str = "copy-file";
+pub const CHECK_FILE_NAME : &'static str = "check-file-name";
+pub const CHECK_FILE_HANDLE : &'static str = "check-file-handle";
+pub const MD5_HASH : &'static str = "md5-hash";
+pub const MD5_HASH_HANDLE : &'static str = "md5-hash-handle";
+pub const POSIX_RENAME : &'static str = "posix-rename@openssh.com";
+pub const FSYNC : &'static str = "fsync@openssh.com";
+pub const LIMITS : &'static str = "limits@openssh.com";
+
+/// A packet a client sends. `Sendable` encodes its body; the framing is added by `PacketWriter`.
 pub trait Request : fmt::Debug + Sendable {
+    /// The SSH_FXP_* message type. Extended requests are SSH_FXP_EXTENDED.
     fn msg_type() -> u8;
+
+    /// What to call the request in logs. Extended requests use the extension name.
+    fn name(&self) -> &'static str { msg_type_name(Self::msg_type()) }
+
+    /// The remote path the request operates on, if it names one, for logs.
+    fn path(&self) -> Option<&[u8]> { None }
+
+    /// Number of file content bytes moved by this request, used for bandwidth limiting.
+    fn payload_len(&self) -> u64 { 0 }
+
+    /// Bulk data at the very end of the packet. The sender writes it straight from the request
+    /// instead of copying it into the packet buffer, after `write_head_to` has written the rest.
+    fn trailing_data(&self) -> &[u8] { &[] }
+
+    /// Writes everything but `trailing_data`.
+    fn write_head_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        self.write_to(w)
+    }
 }
 
+/// Encodes a packet body or a field of one in the wire format.
 pub trait Sendable {
     fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()>;
 
+    /// The number of bytes `write_to` writes.
     fn size(&self) -> u32;
 }
 
+/// A packet a server sends.
 pub trait Response : fmt::Debug + Receivable {
+    /// The SSH_FXP_* message type.
     fn msg_type() -> u8;
 }
 
+/// Decodes a packet body or a field of one from the wire format.
 pub trait Receivable {
-    fn recv<R: io::Read>(r: &mut R) -> Result<Self>;
+    fn recv<R: Decode>(r: &mut R) -> Result<Self>;
 }
 
-#[derive(Debug)]
+/// Input to the decoders. Knows how much of the enclosing frame is left, so that length fields can
+/// be checked before anything is allocated for them. `io::Cursor` implements it for a frame that
+/// is already in memory.
+pub trait Decode : io::Read {
+    /// Bytes left in the frame.
+    fn remaining(&self) -> u64;
+
+    /// Strings longer than this are rejected with `Error::StringTooLong`.
+    fn max_string_len(&self) -> u32 { u32::MAX }
+}
+
+impl<T> Decode for io::Cursor<T> where T: AsRef<[u8]> {
+    fn remaining(&self) -> u64 {
+        (self.get_ref().as_ref().len() as u64).saturating_sub(self.position())
+    }
+}
+
+/// Bounds on what is accepted from the server, to keep a broken or hostile one from making the
+/// client allocate arbitrary amounts of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    pub max_packet_len: u32,
+    pub max_string_len: u32,
+}
+
+impl Default for DecodeLimits {
+    // The same maximum OpenSSH's sftp-server uses for packets in either direction.
+    fn default() -> DecodeLimits {
+        DecodeLimits{max_packet_len: 256 * 1024, max_string_len: 256 * 1024}
+    }
+}
+
+// The body of one frame from the server.
+struct Frame<'a, R: 'a> {
+    r: io::Take<&'a mut R>,
+    max_string_len: u32,
+}
+
+impl<'a, R> io::Read for Frame<'a, R> where R: io::Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.r.read(buf)
+    }
+}
+
+impl<'a, R> Decode for Frame<'a, R> where R: io::Read {
+    fn remaining(&self) -> u64 {
+        self.r.limit()
+    }
+
+    fn max_string_len(&self) -> u32 {
+        self.max_string_len
+    }
+}
+
+// Fails unless `count` items of at least `min_size` bytes each fit in what is left of the frame.
+fn check_count<R: Decode>(r: &R, count: u32, min_size: u64) -> Result<()> {
+    if count as u64 * min_size > r.remaining() {
+        return Err(Error::UnexpectedData);
+    }
+    Ok(())
+}
+
+/// A framed response: the request id it answers and its body. SSH_FXP_VERSION has no request
+/// id and comes with 0.
+#[derive(Debug, PartialEq)]
 pub struct SftpResponse {
     pub req_id : u32,
     pub packet : SftpResponsePacket,
 }
 
-#[derive(Debug)]
+/// The body of any response a version 3 server sends. Message types this crate does not know are
+/// kept as `Unknown`.
+#[derive(Debug, PartialEq)]
 pub enum SftpResponsePacket {
     Version(FxpVersion),
     Status(FxpStatus),
@@ -75,6 +211,80 @@ pub enum SftpResponsePacket {
     Data(FxpData),
     Name(FxpName),
     Attrs(FileAttr),
+    ExtendedReply(FxpExtendedReply),
+    Unknown{msg_type: u8, data: Vec<u8>},
+}
+
+impl SftpResponsePacket {
+    pub fn msg_type(&self) -> u8 {
+        match *self {
+            SftpResponsePacket::Version(_) => SSH_FXP_VERSION,
+            SftpResponsePacket::Status(_) => SSH_FXP_STATUS,
+            SftpResponsePacket::Handle(_) => SSH_FXP_HANDLE,
+            SftpResponsePacket::Data(_) => SSH_FXP_DATA,
+            SftpResponsePacket::Name(_) => SSH_FXP_NAME,
+            SftpResponsePacket::Attrs(_) => SSH_FXP_ATTRS,
+            SftpResponsePacket::ExtendedReply(_) => SSH_FXP_EXTENDED_REPLY,
+            SftpResponsePacket::Unknown{msg_type, ..} => msg_type,
+        }
+    }
+}
+
+// A response as the client's receiver decodes it. SSH_FXP_DATA may have gone straight into a
+// buffer supplied with the request, in which case only the number of bytes stored is left.
+#[derive(Debug)]
+pub enum Received {
+    Packet(SftpResponsePacket),
+    DataInBuffer(usize),
+}
+
+impl Received {
+    pub fn msg_type(&self) -> u8 {
+        match *self {
+            Received::Packet(ref packet) => packet.msg_type(),
+            Received::DataInBuffer(_) => SSH_FXP_DATA,
+        }
+    }
+}
+
+/// A framed request: its request id and body. SSH_FXP_INIT has no request id and comes with 0.
+#[derive(Debug, PartialEq)]
+pub struct SftpRequest {
+    pub req_id : u32,
+    pub packet : SftpRequestPacket,
+}
+
+/// The body of any request a client sends. Extended requests this crate does not know are kept
+/// as `Extended`.
+#[derive(Debug, PartialEq)]
+pub enum SftpRequestPacket {
+    Init(FxpInit),
+    Open(FxpOpen),
+    Close(FxpClose),
+    Read(FxpRead),
+    Write(FxpWrite<'static>),
+    LStat(FxpLStat),
+    FStat(FxpFStat),
+    SetStat(FxpSetStat),
+    FSetStat(FxpFSetStat),
+    OpenDir(FxpOpenDir),
+    ReadDir(FxpReadDir),
+    Remove(FxpRemove),
+    MkDir(FxpMkDir),
+    RmDir(FxpRmDir),
+    RealPath(FxpRealPath),
+    Stat(FxpStat),
+    Rename(FxpRename),
+    ReadLink(FxpReadLink),
+    CopyData(FxpCopyData),
+    CopyFile(FxpCopyFile),
+    CheckFile(FxpCheckFile),
+    Md5Hash(FxpMd5Hash),
+    PosixRename(FxpPosixRename),
+    Fsync(FxpFsync),
+    Limits(FxpLimits),
+    // SSH_FXP_EXTENDED for an extension this crate does not know. `data` follows the name.
+    Extended{name: Vec<u8>, data: Vec<u8>},
     Unknown{msg_type: u8, data: Vec<u8>},
 }
 
@@ -88,8 +298,18 @@ impl Sendable for u8 {
     }
 }
 
+impl Sendable for bool {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        (*self as u8).write_to(w)
+    }
+
+    fn size(&self) -> u32 {
+        1
+    }
+}
+
 impl Receivable for u8 {
-    fn recv<R: io::Read>(r: &mut R) -> Result<u8> {
+    fn recv<R: Decode>(r: &mut R) -> Result<u8> {
         let mut buf = [0;1];
         if try!(r.read(&mut buf)) < 1 {
             return Err(Error::UnexpectedEOF)
@@ -98,6 +318,12 @@ impl Receivable for u8 {
     }
 }
 
+impl Receivable for bool {
+    fn recv<R: Decode>(r: &mut R) -> Result<bool> {
+        Ok(try!(u8::recv(r)) != 0)
+    }
+}
+
 impl Sendable for u32 {
     fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
         Ok(try!(w.write_u32::<BigEndian>(*self)))
@@ -109,7 +335,7 @@ impl Sendable for u32 {
 }
 
 impl Receivable for u32 {
-    fn recv<R: io::Read>(r: &mut R) -> Result<u32> {
+    fn recv<R: Decode>(r: &mut R) -> Result<u32> {
         Ok(try!(r.read_u32::<BigEndian>()))
     }
 }
@@ -125,7 +351,7 @@ impl Sendable for u64 {
 }
 
 impl Receivable for u64 {
-    fn recv<R: io::Read>(r: &mut R) -> Result<u64> {
+    fn recv<R: Decode>(r: &mut R) -> Result<u64> {
         Ok(try!(r.read_u64::<BigEndian>()))
     }
 }
@@ -141,15 +367,28 @@ impl Sendable for Vec<u8> {
     }
 }
 
+impl Sendable for [u8] {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(w.write_u32::<BigEndian>(self.len() as u32));
+        Ok(try!(w.write_all(self)))
+    }
+
+    fn size(&self) -> u32 {
+        4 + self.len() as u32
+    }
+}
+
 impl Receivable for Vec<u8> {
-    fn recv<R: io::Read>(r: &mut R) -> Result<Vec<u8>> {
+    fn recv<R: Decode>(r: &mut R) -> Result<Vec<u8>> {
         let l = try!(r.read_u32::<BigEndian>());
-        let mut s = Vec::with_capacity(l as usize);
-        let mut lr = r.take(l as u64);
-        let n = try!(lr.read_to_end(&mut s));
-        if n != l as usize {
-            io::Error::new(io::ErrorKind::Other, "short read");
+        if l > r.max_string_len() {
+            return Err(Error::StringTooLong(l));
         }
+        if l as u64 > r.remaining() {
+            return Err(Error::UnexpectedData);
+        }
+        let mut s = vec![0; l as usize];
+        try!(r.read_exact(&mut s));
         Ok(s)
     }
 }
@@ -171,12 +410,14 @@ impl<T> Sendable for Option<T> where T : Sendable {
 }
 
 impl<T> Receivable for Option<T> where T : Receivable {
-    fn recv<R: io::Read>(r: &mut R) -> Result<Option<T>> {
+    fn recv<R: Decode>(r: &mut R) -> Result<Option<T>> {
         Ok(Some(try!(T::recv(r))))
     }
 }
 
-#[derive(Debug)]
+/// A name and data pair, as in the extension list of SSH_FXP_INIT and SSH_FXP_VERSION or the
+/// extended attributes of a file.
+#[derive(Debug, Clone, PartialEq)]
 pub struct Extension {
     pub name: Vec<u8>,
     pub data: Vec<u8>,
@@ -194,20 +435,30 @@ impl Sendable for Extension {
 }
 
 impl Receivable for Extension {
-    fn recv<R: io::Read>(r: &mut R) -> Result<Extension> {
+    fn recv<R: Decode>(r: &mut R) -> Result<Extension> {
         let name = try!(Vec::<u8>::recv(r));
         let data = try!(Vec::<u8>::recv(r));
         Ok(Extension{name: name, data: data})
     }
 }
 
-const SSH_FILEXFER_ATTR_SIZE : u32 = 0x00000001;
-const SSH_FILEXFER_ATTR_UIDGID : u32 = 0x00000002;
-const SSH_FILEXFER_ATTR_PERMISSIONS : u32 = 0x00000004;
-const SSH_FILEXFER_ATTR_ACMODTIME : u32 = 0x00000008;
-const SSH_FILEXFER_ATTR_EXTENDED : u32 = 0x80000000;
+pub const SSH_FILEXFER_ATTR_SIZE : u32 = 0x00000001;
+pub const SSH_FILEXFER_ATTR_UIDGID : u32 = 0x00000002;
+pub const SSH_FILEXFER_ATTR_PERMISSIONS : u32 = 0x00000004;
+pub const SSH_FILEXFER_ATTR_ACMODTIME : u32 = 0x00000008;
+pub const SSH_FILEXFER_ATTR_EXTENDED : u32 = 0x80000000;
 
-#[derive(Debug)]
+pub const SSH_FXF_READ : u32 = 0x00000001;
+pub const SSH_FXF_WRITE : u32 = 0x00000002;
+pub const SSH_FXF_APPEND : u32 = 0x00000004;
+pub const SSH_FXF_CREAT : u32 = 0x00000008;
+pub const SSH_FXF_TRUNC : u32 = 0x00000010;
+pub const SSH_FXF_EXCL : u32 = 0x00000020;
+
+/// File attributes, as sent with SETSTAT, OPEN and MKDIR and returned by the stat requests.
+/// `uid` and `gid`, like `atime` and `mtime`, have to be set together; sending only one of a pair
+/// fails with `Error::IncompleteAttrs`.
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileAttr {
     pub size : Option<u64>,
     pub uid : Option<u32>,
@@ -233,7 +484,15 @@ impl FileAttr {
 }
 
 impl Sendable for FileAttr {
+    // uid and gid, like atime and mtime, share a flag and only go out as a pair. Setting just one
+    // of them is refused rather than silently dropped.
     fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        if self.uid.is_some() != self.gid.is_some() {
+            return Err(Error::IncompleteAttrs("uid and gid"));
+        }
+        if self.atime.is_some() != self.mtime.is_some() {
+            return Err(Error::IncompleteAttrs("atime and mtime"));
+        }
         let mut flags : u32 = 0;
         if self.size.is_some() {
             flags |= SSH_FILEXFER_ATTR_SIZE;
@@ -252,24 +511,45 @@ impl Sendable for FileAttr {
         }
         try!(flags.write_to(w));
         try!(self.size.write_to(w));
-        try!(self.uid.write_to(w));
-        try!(self.gid.write_to(w));
-        try!(self.atime.write_to(w));
-        try!(self.mtime.write_to(w));
-        for extension in self.extensions.iter() {
-            try!(extension.write_to(w));
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            try!(self.uid.write_to(w));
+            try!(self.gid.write_to(w));
+        }
+        try!(self.perms.write_to(w));
+        if flags & SSH_FILEXFER_ATTR_ACMODTIME != 0 {
+            try!(self.atime.write_to(w));
+            try!(self.mtime.write_to(w));
+        }
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            try!((self.extensions.len() as u32).write_to(w));
+            for extension in self.extensions.iter() {
+                try!(extension.write_to(w));
+            }
         }
         Ok(())
     }
 
     fn size(&self) -> u32 {
-        return 4 + self.size.size() + self.uid.size() + self.gid.size() + self.atime.size() +
-            self.mtime.size() + self.extensions.iter().fold(0, |acc, e| acc + e.size());
+        let mut size = 4 + self.size.size() + self.perms.size();
+        if self.uid.is_some() && self.gid.is_some() {
+            size += 8;
+        }
+        if self.atime.is_some() && self.mtime.is_some() {
+            size += 8;
+        }
+        if self.extensions.len() > 0 {
+            size += 4 + self.extensions.iter().fold(0, |acc, e| acc + e.size());
+        }
+        size
     }
 }
 
+impl Response for FileAttr {
+    fn msg_type() -> u8 { SSH_FXP_ATTRS }
+}
+
 impl Receivable for FileAttr {
-    fn recv<R: io::Read>(r: &mut R) -> Result<FileAttr> {
+    fn recv<R: Decode>(r: &mut R) -> Result<FileAttr> {
         let flags = try!(r.read_u32::<BigEndian>());
         let size = if flags & SSH_FILEXFER_ATTR_SIZE != 0 {
             try!(Option::<u64>::recv(r))
@@ -293,6 +573,7 @@ impl Receivable for FileAttr {
         };
         let extensions = if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
             let ext_count = try!(u32::recv(r));
+            try!(check_count(r, ext_count, 8));
             let mut extensions = Vec::new();
             for _ in 0..ext_count {
                 extensions.push(try!(Extension::recv(r)));
@@ -305,7 +586,8 @@ impl Receivable for FileAttr {
     }
 }
 
-#[derive(Debug)]
+/// SSH_FXP_INIT, the first packet a client sends.
+#[derive(Debug, PartialEq)]
 pub struct FxpInit {
 	pub version: u32,
 	pub extensions: Vec<Extension>,
@@ -329,7 +611,20 @@ impl Sendable for FxpInit {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpInit {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpInit> {
+        let version = try!(u32::recv(r));
+        let mut extensions = Vec::new();
+        while r.remaining() > 0 {
+            extensions.push(try!(Extension::recv(r)));
+        }
+        Ok(FxpInit{version: version, extensions: extensions})
+    }
+}
+
+/// SSH_FXP_OPEN. `pflags` is a combination of the SSH_FXF_* flags; the server answers with a
+/// handle.
+#[derive(Debug, PartialEq)]
 pub struct FxpOpen {
     pub filename : Vec<u8>,
     pub pflags : u32,
@@ -338,6 +633,8 @@ pub struct FxpOpen {
 
 impl Request for FxpOpen {
     fn msg_type() -> u8 { SSH_FXP_OPEN }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.filename) }
 }
 
 impl Sendable for FxpOpen {
@@ -352,7 +649,18 @@ impl Sendable for FxpOpen {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpOpen {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpOpen> {
+        Ok(FxpOpen{
+            filename: try!(Vec::<u8>::recv(r)),
+            pflags: try!(u32::recv(r)),
+            attrs: try!(FileAttr::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_CLOSE, for handles from both SSH_FXP_OPEN and SSH_FXP_OPENDIR.
+#[derive(Debug, PartialEq)]
 pub struct FxpClose {
     pub handle: Vec<u8>,
 }
@@ -371,7 +679,16 @@ impl Sendable for FxpClose {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpClose {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpClose> {
+        Ok(FxpClose{
+            handle: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_READ of at most `len` bytes at `offset`. The server answers with data or status.
+#[derive(Debug, PartialEq)]
 pub struct FxpRead {
     pub handle: Vec<u8>,
     pub offset: u64,
@@ -380,6 +697,8 @@ pub struct FxpRead {
 
 impl Request for FxpRead {
     fn msg_type() -> u8 { SSH_FXP_READ }
+
+    fn payload_len(&self) -> u64 { self.len as u64 }
 }
 
 impl Sendable for FxpRead {
@@ -394,22 +713,43 @@ impl Sendable for FxpRead {
     }
 }
 
-#[derive(Debug)]
-pub struct FxpWrite {
-    pub handle: Vec<u8>,
+impl Receivable for FxpRead {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpRead> {
+        Ok(FxpRead{
+            handle: try!(Vec::<u8>::recv(r)),
+            offset: try!(u64::recv(r)),
+            len: try!(u32::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_WRITE. The handle and the data may be borrowed, and the data is written out without
+/// being copied into the packet, see `Request::trailing_data`.
+#[derive(Debug, PartialEq)]
+pub struct FxpWrite<'a> {
+    pub handle: Cow<'a, [u8]>,
     pub offset: u64,
-    pub data: Vec<u8>,
+    pub data: Cow<'a, [u8]>,
 }
 
-impl Request for FxpWrite {
+impl<'a> Request for FxpWrite<'a> {
     fn msg_type() -> u8 { SSH_FXP_WRITE }
-}
 
-impl Sendable for FxpWrite {
-    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+    fn payload_len(&self) -> u64 { self.data.len() as u64 }
+
+    fn trailing_data(&self) -> &[u8] { &self.data }
+
+    fn write_head_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
         try!(self.handle.write_to(w));
         try!(self.offset.write_to(w));
-        Ok(try!(self.data.write_to(w)))
+        Ok(try!(w.write_u32::<BigEndian>(self.data.len() as u32)))
+    }
+}
+
+impl<'a> Sendable for FxpWrite<'a> {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(self.write_head_to(w));
+        Ok(try!(w.write_all(&self.data)))
     }
 
     fn size(&self) -> u32 {
@@ -417,13 +757,26 @@ impl Sendable for FxpWrite {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpWrite<'static> {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpWrite<'static>> {
+        Ok(FxpWrite{
+            handle: Cow::Owned(try!(Vec::<u8>::recv(r))),
+            offset: try!(u64::recv(r)),
+            data: Cow::Owned(try!(Vec::<u8>::recv(r))),
+        })
+    }
+}
+
+/// SSH_FXP_LSTAT: the attributes of a path, not following a final symlink.
+#[derive(Debug, PartialEq)]
 pub struct FxpLStat {
     pub path : Vec<u8>
 }
 
 impl Request for FxpLStat {
     fn msg_type() -> u8 { SSH_FXP_LSTAT }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.path) }
 }
 
 impl Sendable for FxpLStat {
@@ -436,7 +789,16 @@ impl Sendable for FxpLStat {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpLStat {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpLStat> {
+        Ok(FxpLStat{
+            path: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_FSTAT: the attributes of an open handle.
+#[derive(Debug, PartialEq)]
 pub struct FxpFStat {
     pub handle : Vec<u8>
 }
@@ -455,7 +817,16 @@ impl Sendable for FxpFStat {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpFStat {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpFStat> {
+        Ok(FxpFStat{
+            handle: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_SETSTAT: changes the attributes of a path.
+#[derive(Debug, PartialEq)]
 pub struct FxpSetStat {
     pub path : Vec<u8>,
     pub attrs : FileAttr,
@@ -463,6 +834,8 @@ pub struct FxpSetStat {
 
 impl Request for FxpSetStat {
     fn msg_type() -> u8 { SSH_FXP_SETSTAT }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.path) }
 }
 
 impl Sendable for FxpSetStat {
@@ -476,7 +849,17 @@ impl Sendable for FxpSetStat {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpSetStat {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpSetStat> {
+        Ok(FxpSetStat{
+            path: try!(Vec::<u8>::recv(r)),
+            attrs: try!(FileAttr::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_FSETSTAT: changes the attributes of an open handle.
+#[derive(Debug, PartialEq)]
 pub struct FxpFSetStat {
     pub handle : Vec<u8>,
     pub attrs : FileAttr,
@@ -497,13 +880,25 @@ impl Sendable for FxpFSetStat {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpFSetStat {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpFSetStat> {
+        Ok(FxpFSetStat{
+            handle: try!(Vec::<u8>::recv(r)),
+            attrs: try!(FileAttr::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_OPENDIR. The server answers with a handle to pass to `FxpReadDir`.
+#[derive(Debug, PartialEq)]
 pub struct FxpOpenDir {
     pub path : Vec<u8>,
 }
 
 impl Request for FxpOpenDir {
     fn msg_type() -> u8 { SSH_FXP_OPENDIR }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.path) }
 }
 
 impl Sendable for FxpOpenDir {
@@ -516,7 +911,16 @@ impl Sendable for FxpOpenDir {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpOpenDir {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpOpenDir> {
+        Ok(FxpOpenDir{
+            path: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_READDIR: the next batch of entries, or status EOF once there are none left.
+#[derive(Debug, PartialEq)]
 pub struct FxpReadDir {
     pub handle : Vec<u8>,
 }
@@ -535,13 +939,24 @@ impl Sendable for FxpReadDir {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpReadDir {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpReadDir> {
+        Ok(FxpReadDir{
+            handle: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_REMOVE: removes a file.
+#[derive(Debug, PartialEq)]
 pub struct FxpRemove {
     pub filename : Vec<u8>
 }
 
 impl Request for FxpRemove {
     fn msg_type() -> u8 { SSH_FXP_REMOVE }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.filename) }
 }
 
 impl Sendable for FxpRemove {
@@ -554,7 +969,16 @@ impl Sendable for FxpRemove {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpRemove {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpRemove> {
+        Ok(FxpRemove{
+            filename: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_MKDIR with the attributes for the new directory.
+#[derive(Debug, PartialEq)]
 pub struct FxpMkDir {
     pub path : Vec<u8>,
     pub attrs : FileAttr,
@@ -562,6 +986,8 @@ pub struct FxpMkDir {
 
 impl Request for FxpMkDir {
     fn msg_type() -> u8 { SSH_FXP_MKDIR }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.path) }
 }
 
 impl Sendable for FxpMkDir {
@@ -575,13 +1001,25 @@ impl Sendable for FxpMkDir {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpMkDir {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpMkDir> {
+        Ok(FxpMkDir{
+            path: try!(Vec::<u8>::recv(r)),
+            attrs: try!(FileAttr::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_RMDIR: removes an empty directory.
+#[derive(Debug, PartialEq)]
 pub struct FxpRmDir {
     pub path : Vec<u8>
 }
 
 impl Request for FxpRmDir {
     fn msg_type() -> u8 { SSH_FXP_RMDIR }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.path) }
 }
 
 impl Sendable for FxpRmDir {
@@ -594,13 +1032,24 @@ impl Sendable for FxpRmDir {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpRmDir {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpRmDir> {
+        Ok(FxpRmDir{
+            path: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_REALPATH: the canonical absolute form of a path, returned as a single name.
+#[derive(Debug, PartialEq)]
 pub struct FxpRealPath {
     pub path : Vec<u8>
 }
 
 impl Request for FxpRealPath {
     fn msg_type() -> u8 { SSH_FXP_REALPATH }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.path) }
 }
 
 impl Sendable for FxpRealPath {
@@ -613,13 +1062,24 @@ impl Sendable for FxpRealPath {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpRealPath {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpRealPath> {
+        Ok(FxpRealPath{
+            path: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_STAT: the attributes of a path, following symlinks.
+#[derive(Debug, PartialEq)]
 pub struct FxpStat {
     pub path : Vec<u8>
 }
 
 impl Request for FxpStat {
     fn msg_type() -> u8 { SSH_FXP_STAT }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.path) }
 }
 
 impl Sendable for FxpStat {
@@ -632,7 +1092,16 @@ impl Sendable for FxpStat {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpStat {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpStat> {
+        Ok(FxpStat{
+            path: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_RENAME. Most servers refuse to replace an existing `newpath`, see `FxpPosixRename`.
+#[derive(Debug, PartialEq)]
 pub struct FxpRename {
     pub oldpath : Vec<u8>,
     pub newpath : Vec<u8>,
@@ -640,6 +1109,8 @@ pub struct FxpRename {
 
 impl Request for FxpRename {
     fn msg_type() -> u8 { SSH_FXP_RENAME }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.oldpath) }
 }
 
 impl Sendable for FxpRename {
@@ -653,13 +1124,25 @@ impl Sendable for FxpRename {
     }
 }
 
-#[derive(Debug)]
+impl Receivable for FxpRename {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpRename> {
+        Ok(FxpRename{
+            oldpath: try!(Vec::<u8>::recv(r)),
+            newpath: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_READLINK: the target of a symlink, returned as a single name.
+#[derive(Debug, PartialEq)]
 pub struct FxpReadLink {
     pub path : Vec<u8>
 }
 
 impl Request for FxpReadLink {
     fn msg_type() -> u8 { SSH_FXP_READLINK }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.path) }
 }
 
 impl Sendable for FxpReadLink {
@@ -672,131 +1155,537 @@ impl Sendable for FxpReadLink {
     }
 }
 
-#[derive(Debug)]
-pub struct FxpVersion {
-    pub version: u32,
-    pub extensions: Vec<Extension>,
+impl Receivable for FxpReadLink {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpReadLink> {
+        Ok(FxpReadLink{
+            path: try!(Vec::<u8>::recv(r)),
+        })
+    }
 }
 
-impl Response for FxpVersion {
-    fn msg_type() -> u8 { SSH_FXP_VERSION }
+// Extended requests start with the name of the extension.
+fn expect_extension<R: Decode>(r: &mut R, name: &'static str) -> Result<()> {
+    try!(recv_extension(r, &[name]));
+    Ok(())
 }
 
-impl Receivable for FxpVersion {
-    fn recv<R: io::Read>(r: &mut R) -> Result<FxpVersion> {
-            let version = try!(u32::recv(r));
-            let mut bytes = Vec::new();
-            let limit = try!(r.read_to_end(&mut bytes));
-            let mut extensions = Vec::new();
-            let mut er = io::Cursor::new(bytes);
-            while er.position() < limit as u64 {
-                extensions.push(try!(Extension::recv(&mut er)));
-            }
-            Ok(FxpVersion{version: version, extensions: extensions})
+fn recv_extension<R: Decode>(r: &mut R, names: &[&'static str]) -> Result<&'static str> {
+    let name = try!(Vec::<u8>::recv(r));
+    match names.iter().find(|n| n.as_bytes() == &name[..]) {
+        Some(n) => Ok(n),
+        None => Err(Error::UnexpectedData),
     }
 }
 
-const SSH_FX_OK : u32 = 0;
-const SSH_FX_EOF : u32 = 1;
-const SSH_FX_NO_SUCH_FILE : u32 = 2;
-const SSH_FX_PERMISSION_DENIED : u32 = 3;
-const SSH_FX_FAILURE : u32 = 4;
-const SSH_FX_BAD_MESSAGE : u32 = 5;
-const SSH_FX_NO_CONNECTION : u32 = 6;
-const SSH_FX_CONNECTION_LOST : u32 = 7;
-const SSH_FX_OP_UNSUPPORTED : u32 = 8;
+/// The copy-data extension: copies `read_len` bytes, or up to the end of the file if zero, between
+/// two open handles without sending the data to the client.
+#[derive(Debug, PartialEq)]
+pub struct FxpCopyData {
+    pub read_handle : Vec<u8>,
+    pub read_offset : u64,
+    pub read_len : u64,
+    pub write_handle : Vec<u8>,
+    pub write_offset : u64,
+}
 
+impl Request for FxpCopyData {
+    fn msg_type() -> u8 { SSH_FXP_EXTENDED }
 
-#[derive(Debug)]
-pub enum FxpStatusCode {
-    Ok,
-    EOF,
-    NoSuchFile,
-    PermissionDenied,
-    Failure,
-    BadMessage,
-    NoConnection,
-    ConnectionLost,
-    OpUnsupported,
-    UnknownCode(Vec<u8>),
+    fn name(&self) -> &'static str { COPY_DATA }
 }
 
-#[derive(Debug)]
-pub struct FxpStatus {
-    pub code: FxpStatusCode,
-    pub msg: String,
-}
+impl Sendable for FxpCopyData {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(COPY_DATA.as_bytes().write_to(w));
+        try!(self.read_handle.write_to(w));
+        try!(self.read_offset.write_to(w));
+        try!(self.read_len.write_to(w));
+        try!(self.write_handle.write_to(w));
+        Ok(try!(self.write_offset.write_to(w)))
+    }
 
-impl Response for FxpStatus {
-    fn msg_type() -> u8 { SSH_FXP_STATUS }
+    fn size(&self) -> u32 {
+        COPY_DATA.as_bytes().size() + self.read_handle.size() + self.read_offset.size() +
+            self.read_len.size() + self.write_handle.size() + self.write_offset.size()
+    }
 }
 
-impl Receivable for FxpStatus {
-    fn recv<R: io::Read>(r: &mut R) -> Result<FxpStatus> {
-        let icode = try!(u32::recv(r));
-        let msg = try!(Vec::<u8>::recv(r));
-        try!(Vec::<u8>::recv(r));  // Skip lang
-        let code = match icode {
-            SSH_FX_OK => FxpStatusCode::Ok,
-            SSH_FX_EOF => FxpStatusCode::EOF,
-            SSH_FX_NO_SUCH_FILE => FxpStatusCode::NoSuchFile,
-            SSH_FX_PERMISSION_DENIED => FxpStatusCode::PermissionDenied,
-            SSH_FX_FAILURE => FxpStatusCode::Failure,
-            SSH_FX_BAD_MESSAGE => FxpStatusCode::BadMessage,
-            SSH_FX_NO_CONNECTION => FxpStatusCode::NoConnection,
-            SSH_FX_CONNECTION_LOST => FxpStatusCode::ConnectionLost,
-            SSH_FX_OP_UNSUPPORTED => FxpStatusCode::OpUnsupported,
-            _ => {
-                let mut data = Vec::new();
-                try!(r.read_to_end(&mut data));
-                FxpStatusCode::UnknownCode(data)
-            },
-        };
-        Ok(FxpStatus{code: code, msg: try!(String::from_utf8(msg))})
+impl Receivable for FxpCopyData {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpCopyData> {
+        try!(expect_extension(r, COPY_DATA));
+        Ok(FxpCopyData{
+            read_handle: try!(Vec::<u8>::recv(r)),
+            read_offset: try!(u64::recv(r)),
+            read_len: try!(u64::recv(r)),
+            write_handle: try!(Vec::<u8>::recv(r)),
+            write_offset: try!(u64::recv(r)),
+        })
     }
 }
 
-impl StdError for FxpStatus {
-    fn description(&self) -> &str {
-        return self.msg.as_str();
-    }
+/// The copy-file extension: copies a file by path on the server.
+#[derive(Debug, PartialEq)]
+pub struct FxpCopyFile {
+    pub source : Vec<u8>,
+    pub destination : Vec<u8>,
+    pub overwrite : bool,
+}
 
-    fn cause(&self) -> Option<&StdError> { None }
+impl Request for FxpCopyFile {
+    fn msg_type() -> u8 { SSH_FXP_EXTENDED }
+
+    fn name(&self) -> &'static str { COPY_FILE }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.source) }
 }
 
-impl fmt::Display for FxpStatus {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}: {}", self.code, self.msg)
+impl Sendable for FxpCopyFile {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(COPY_FILE.as_bytes().write_to(w));
+        try!(self.source.write_to(w));
+        try!(self.destination.write_to(w));
+        Ok(try!(self.overwrite.write_to(w)))
+    }
+
+    fn size(&self) -> u32 {
+        COPY_FILE.as_bytes().size() + self.source.size() + self.destination.size() + self.overwrite.size()
     }
 }
 
-impl From<FxpStatus> for io::Error {
-    fn from(err: FxpStatus) -> io::Error {
-        let ek = match err.code {
-            FxpStatusCode::NoSuchFile => io::ErrorKind::NotFound,
-            FxpStatusCode::PermissionDenied => io::ErrorKind::PermissionDenied,
-            _ => io::ErrorKind::Other,
-        };
-        io::Error::new(ek, err)
+impl Receivable for FxpCopyFile {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpCopyFile> {
+        try!(expect_extension(r, COPY_FILE));
+        Ok(FxpCopyFile{
+            source: try!(Vec::<u8>::recv(r)),
+            destination: try!(Vec::<u8>::recv(r)),
+            overwrite: try!(bool::recv(r)),
+        })
     }
 }
 
-#[derive(Debug)]
-pub struct FxpHandle {
-    pub handle: Vec<u8>,
+/// Covers both check-file-name and check-file-handle; `name` holds either the path or the handle.
+#[derive(Debug, PartialEq)]
+pub struct FxpCheckFile {
+    pub extension : &'static str,
+    pub name : Vec<u8>,
+    pub hash_algorithms : Vec<u8>,
+    pub start_offset : u64,
+    pub length : u64,
+    pub block_size : u32,
+}
+
+impl Request for FxpCheckFile {
+    fn msg_type() -> u8 { SSH_FXP_EXTENDED }
+
+    fn name(&self) -> &'static str { self.extension }
+
+    fn path(&self) -> Option<&[u8]> {
+        if self.extension == CHECK_FILE_NAME { Some(&self.name) } else { None }
+    }
+}
+
+impl Sendable for FxpCheckFile {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(self.extension.as_bytes().write_to(w));
+        try!(self.name.write_to(w));
+        try!(self.hash_algorithms.write_to(w));
+        try!(self.start_offset.write_to(w));
+        try!(self.length.write_to(w));
+        Ok(try!(self.block_size.write_to(w)))
+    }
+
+    fn size(&self) -> u32 {
+        self.extension.as_bytes().size() + self.name.size() + self.hash_algorithms.size() +
+            self.start_offset.size() + self.length.size() + self.block_size.size()
+    }
+}
+
+impl Receivable for FxpCheckFile {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpCheckFile> {
+        Ok(FxpCheckFile{
+            extension: try!(recv_extension(r, &[CHECK_FILE_NAME, CHECK_FILE_HANDLE])),
+            name: try!(Vec::<u8>::recv(r)),
+            hash_algorithms: try!(Vec::<u8>::recv(r)),
+            start_offset: try!(u64::recv(r)),
+            length: try!(u64::recv(r)),
+            block_size: try!(u32::recv(r)),
+        })
+    }
+}
+
+/// Covers both md5-hash and md5-hash-handle; `name` holds either the path or the handle.
+#[derive(Debug, PartialEq)]
+pub struct FxpMd5Hash {
+    pub extension : &'static str,
+    pub name : Vec<u8>,
+    pub start_offset : u64,
+    pub length : u64,
+    pub quick_check_hash : Vec<u8>,
+}
+
+impl Request for FxpMd5Hash {
+    fn msg_type() -> u8 { SSH_FXP_EXTENDED }
+
+    fn name(&self) -> &'static str { self.extension }
+
+    fn path(&self) -> Option<&[u8]> {
+        if self.extension == MD5_HASH { Some(&self.name) } else { None }
+    }
+}
+
+impl Sendable for FxpMd5Hash {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(self.extension.as_bytes().write_to(w));
+        try!(self.name.write_to(w));
+        try!(self.start_offset.write_to(w));
+        try!(self.length.write_to(w));
+        Ok(try!(self.quick_check_hash.write_to(w)))
+    }
+
+    fn size(&self) -> u32 {
+        self.extension.as_bytes().size() + self.name.size() + self.start_offset.size() +
+            self.length.size() + self.quick_check_hash.size()
+    }
+}
+
+impl Receivable for FxpMd5Hash {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpMd5Hash> {
+        Ok(FxpMd5Hash{
+            extension: try!(recv_extension(r, &[MD5_HASH, MD5_HASH_HANDLE])),
+            name: try!(Vec::<u8>::recv(r)),
+            start_offset: try!(u64::recv(r)),
+            length: try!(u64::recv(r)),
+            quick_check_hash: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// Unlike SSH_FXP_RENAME, replaces an existing newpath atomically.
+#[derive(Debug, PartialEq)]
+pub struct FxpPosixRename {
+    pub oldpath : Vec<u8>,
+    pub newpath : Vec<u8>,
+}
+
+impl Request for FxpPosixRename {
+    fn msg_type() -> u8 { SSH_FXP_EXTENDED }
+
+    fn name(&self) -> &'static str { POSIX_RENAME }
+
+    fn path(&self) -> Option<&[u8]> { Some(&self.oldpath) }
+}
+
+impl Sendable for FxpPosixRename {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(POSIX_RENAME.as_bytes().write_to(w));
+        try!(self.oldpath.write_to(w));
+        Ok(try!(self.newpath.write_to(w)))
+    }
+
+    fn size(&self) -> u32 {
+        POSIX_RENAME.as_bytes().size() + self.oldpath.size() + self.newpath.size()
+    }
+}
+
+impl Receivable for FxpPosixRename {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpPosixRename> {
+        try!(expect_extension(r, POSIX_RENAME));
+        Ok(FxpPosixRename{
+            oldpath: try!(Vec::<u8>::recv(r)),
+            newpath: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// The fsync@openssh.com extension: flushes an open handle to disk.
+#[derive(Debug, PartialEq)]
+pub struct FxpFsync {
+    pub handle : Vec<u8>,
+}
+
+impl Request for FxpFsync {
+    fn msg_type() -> u8 { SSH_FXP_EXTENDED }
+
+    fn name(&self) -> &'static str { FSYNC }
+}
+
+impl Sendable for FxpFsync {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(FSYNC.as_bytes().write_to(w));
+        Ok(try!(self.handle.write_to(w)))
+    }
+
+    fn size(&self) -> u32 {
+        FSYNC.as_bytes().size() + self.handle.size()
+    }
+}
+
+impl Receivable for FxpFsync {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpFsync> {
+        try!(expect_extension(r, FSYNC));
+        Ok(FxpFsync{
+            handle: try!(Vec::<u8>::recv(r)),
+        })
+    }
+}
+
+/// The limits@openssh.com extension. The reply decodes as `Limits`.
+#[derive(Debug, PartialEq)]
+pub struct FxpLimits;
+
+impl Request for FxpLimits {
+    fn msg_type() -> u8 { SSH_FXP_EXTENDED }
+
+    fn name(&self) -> &'static str { LIMITS }
+}
+
+impl Sendable for FxpLimits {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        LIMITS.as_bytes().write_to(w)
+    }
+
+    fn size(&self) -> u32 {
+        LIMITS.as_bytes().size()
+    }
+}
+
+impl Receivable for FxpLimits {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpLimits> {
+        try!(expect_extension(r, LIMITS));
+        Ok(FxpLimits)
+    }
+}
+
+/// Limits reported by servers that support limits@openssh.com. A zero means the server did not
+/// state a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    pub max_packet_len: u64,
+    pub max_read_len: u64,
+    pub max_write_len: u64,
+    pub max_open_handles: u64,
+}
+
+impl Sendable for Limits {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(self.max_packet_len.write_to(w));
+        try!(self.max_read_len.write_to(w));
+        try!(self.max_write_len.write_to(w));
+        self.max_open_handles.write_to(w)
+    }
+
+    fn size(&self) -> u32 {
+        32
+    }
+}
+
+impl Receivable for Limits {
+    fn recv<R: Decode>(r: &mut R) -> Result<Limits> {
+        Ok(Limits{
+            max_packet_len: try!(u64::recv(r)),
+            max_read_len: try!(u64::recv(r)),
+            max_write_len: try!(u64::recv(r)),
+            max_open_handles: try!(u64::recv(r)),
+        })
+    }
+}
+
+/// SSH_FXP_VERSION, the server's answer to `FxpInit`, listing the extensions it supports.
+#[derive(Debug, PartialEq)]
+pub struct FxpVersion {
+    pub version: u32,
+    pub extensions: Vec<Extension>,
+}
+
+impl Response for FxpVersion {
+    fn msg_type() -> u8 { SSH_FXP_VERSION }
+}
+
+impl Sendable for FxpVersion {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(self.version.write_to(w));
+        for e in self.extensions.iter() {
+            try!(e.write_to(w));
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u32 {
+        self.version.size() + self.extensions.iter().fold(0, |acc, e| acc + e.size())
+    }
+}
+
+impl Receivable for FxpVersion {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpVersion> {
+            let version = try!(u32::recv(r));
+            let mut extensions = Vec::new();
+            while r.remaining() > 0 {
+                extensions.push(try!(Extension::recv(r)));
+            }
+            Ok(FxpVersion{version: version, extensions: extensions})
+    }
+}
+
+pub const SSH_FX_OK : u32 = 0;
+pub const SSH_FX_EOF : u32 = 1;
+pub const SSH_FX_NO_SUCH_FILE : u32 = 2;
+pub const SSH_FX_PERMISSION_DENIED : u32 = 3;
+pub const SSH_FX_FAILURE : u32 = 4;
+pub const SSH_FX_BAD_MESSAGE : u32 = 5;
+pub const SSH_FX_NO_CONNECTION : u32 = 6;
+pub const SSH_FX_CONNECTION_LOST : u32 = 7;
+pub const SSH_FX_OP_UNSUPPORTED : u32 = 8;
+
+
+/// The SSH_FX_* codes of a status response. Codes outside version 3 are kept as `UnknownCode`.
+#[derive(Debug, PartialEq)]
+pub enum FxpStatusCode {
+    Ok,
+    EOF,
+    NoSuchFile,
+    PermissionDenied,
+    Failure,
+    BadMessage,
+    NoConnection,
+    ConnectionLost,
+    OpUnsupported,
+    UnknownCode(u32),
+}
+
+impl FxpStatusCode {
+    pub fn from_code(code: u32) -> FxpStatusCode {
+        match code {
+            SSH_FX_OK => FxpStatusCode::Ok,
+            SSH_FX_EOF => FxpStatusCode::EOF,
+            SSH_FX_NO_SUCH_FILE => FxpStatusCode::NoSuchFile,
+            SSH_FX_PERMISSION_DENIED => FxpStatusCode::PermissionDenied,
+            SSH_FX_FAILURE => FxpStatusCode::Failure,
+            SSH_FX_BAD_MESSAGE => FxpStatusCode::BadMessage,
+            SSH_FX_NO_CONNECTION => FxpStatusCode::NoConnection,
+            SSH_FX_CONNECTION_LOST => FxpStatusCode::ConnectionLost,
+            SSH_FX_OP_UNSUPPORTED => FxpStatusCode::OpUnsupported,
+            code => FxpStatusCode::UnknownCode(code),
+        }
+    }
+
+    /// A short, stable name for the code, e.g. for metric labels.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FxpStatusCode::Ok => "ok",
+            FxpStatusCode::EOF => "eof",
+            FxpStatusCode::NoSuchFile => "no_such_file",
+            FxpStatusCode::PermissionDenied => "permission_denied",
+            FxpStatusCode::Failure => "failure",
+            FxpStatusCode::BadMessage => "bad_message",
+            FxpStatusCode::NoConnection => "no_connection",
+            FxpStatusCode::ConnectionLost => "connection_lost",
+            FxpStatusCode::OpUnsupported => "op_unsupported",
+            FxpStatusCode::UnknownCode(_) => "unknown",
+        }
+    }
+
+    /// The numeric code sent on the wire.
+    pub fn code(&self) -> u32 {
+        match *self {
+            FxpStatusCode::Ok => SSH_FX_OK,
+            FxpStatusCode::EOF => SSH_FX_EOF,
+            FxpStatusCode::NoSuchFile => SSH_FX_NO_SUCH_FILE,
+            FxpStatusCode::PermissionDenied => SSH_FX_PERMISSION_DENIED,
+            FxpStatusCode::Failure => SSH_FX_FAILURE,
+            FxpStatusCode::BadMessage => SSH_FX_BAD_MESSAGE,
+            FxpStatusCode::NoConnection => SSH_FX_NO_CONNECTION,
+            FxpStatusCode::ConnectionLost => SSH_FX_CONNECTION_LOST,
+            FxpStatusCode::OpUnsupported => SSH_FX_OP_UNSUPPORTED,
+            FxpStatusCode::UnknownCode(code) => code,
+        }
+    }
+}
+
+/// SSH_FXP_STATUS: the outcome of requests that return nothing else, and the error for all others.
+#[derive(Debug, PartialEq)]
+pub struct FxpStatus {
+    pub code: FxpStatusCode,
+    pub msg: String,
+}
+
+impl Response for FxpStatus {
+    fn msg_type() -> u8 { SSH_FXP_STATUS }
+}
+
+impl Sendable for FxpStatus {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(self.code.code().write_to(w));
+        try!(self.msg.as_bytes().write_to(w));
+        b"".write_to(w)  // lang
+    }
+
+    fn size(&self) -> u32 {
+        4 + self.msg.as_bytes().size() + 4
+    }
+}
+
+impl Receivable for FxpStatus {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpStatus> {
+        let icode = try!(u32::recv(r));
+        let msg = try!(Vec::<u8>::recv(r));
+        try!(Vec::<u8>::recv(r));  // Skip lang
+        Ok(FxpStatus{code: FxpStatusCode::from_code(icode), msg: try!(String::from_utf8(msg))})
+    }
+}
+
+impl StdError for FxpStatus {
+    fn description(&self) -> &str {
+        return self.msg.as_str();
+    }
+
+    fn cause(&self) -> Option<&StdError> { None }
+}
+
+impl fmt::Display for FxpStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.msg)
+    }
+}
+
+impl From<FxpStatus> for io::Error {
+    fn from(err: FxpStatus) -> io::Error {
+        let ek = match err.code {
+            FxpStatusCode::NoSuchFile => io::ErrorKind::NotFound,
+            FxpStatusCode::PermissionDenied => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(ek, err)
+    }
+}
+
+/// SSH_FXP_HANDLE: an opaque handle to an open file or directory.
+#[derive(Debug, PartialEq)]
+pub struct FxpHandle {
+    pub handle: Vec<u8>,
 }
 
 impl Response for FxpHandle {
     fn msg_type() -> u8 { SSH_FXP_HANDLE }
 }
 
+impl Sendable for FxpHandle {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        self.handle.write_to(w)
+    }
+
+    fn size(&self) -> u32 {
+        self.handle.size()
+    }
+}
+
 impl Receivable for FxpHandle {
-    fn recv<R: io::Read>(r: &mut R) -> Result<FxpHandle> {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpHandle> {
         Ok(FxpHandle{handle: try!(Vec::<u8>::recv(r))})
     }
 }
 
-#[derive(Debug)]
+/// SSH_FXP_DATA: the data returned by a read, possibly less than was asked for.
+#[derive(Debug, PartialEq)]
 pub struct FxpData {
     pub data: Vec<u8>,
 }
@@ -805,21 +1694,86 @@ impl Response for FxpData {
     fn msg_type() -> u8 { SSH_FXP_DATA }
 }
 
+impl Sendable for FxpData {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        self.data.write_to(w)
+    }
+
+    fn size(&self) -> u32 {
+        self.data.size()
+    }
+}
+
 impl Receivable for FxpData {
-    fn recv<R: io::Read>(r: &mut R) -> Result<FxpData> {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpData> {
         Ok(FxpData{data: try!(Vec::<u8>::recv(r))})
     }
 }
 
-#[derive(Debug)]
+impl FxpData {
+    // Reads the data into `buf` rather than a new Vec. More data than was asked for is an error.
+    fn recv_into<R: Decode>(r: &mut R, buf: &mut [u8]) -> Result<usize> {
+        let len = try!(r.read_u32::<BigEndian>()) as usize;
+        if len > buf.len() || len as u64 > r.remaining() {
+            return Err(Error::UnexpectedData);
+        }
+        try!(r.read_exact(&mut buf[..len]));
+        Ok(len)
+    }
+}
+
+/// SSH_FXP_EXTENDED_REPLY: the undecoded reply to an extended request.
+#[derive(Debug, PartialEq)]
+pub struct FxpExtendedReply {
+    pub data: Vec<u8>,
+}
+
+impl Response for FxpExtendedReply {
+    fn msg_type() -> u8 { SSH_FXP_EXTENDED_REPLY }
+}
+
+impl Sendable for FxpExtendedReply {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        Ok(try!(w.write_all(&self.data)))
+    }
+
+    fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+}
+
+impl Receivable for FxpExtendedReply {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpExtendedReply> {
+        // The format of the reply depends on the request, so leave the decoding to the caller.
+        let mut data = Vec::new();
+        try!(r.read_to_end(&mut data));
+        Ok(FxpExtendedReply{data: data})
+    }
+}
+
+/// One entry of an SSH_FXP_NAME response. `longname` is an `ls -l` style line and only
+/// meaningful in directory listings.
+#[derive(Debug, PartialEq)]
 pub struct Name {
     pub filename: Vec<u8>,
     pub longname: Vec<u8>,
     pub attrs: FileAttr,
 }
 
+impl Sendable for Name {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!(self.filename.write_to(w));
+        try!(self.longname.write_to(w));
+        self.attrs.write_to(w)
+    }
+
+    fn size(&self) -> u32 {
+        self.filename.size() + self.longname.size() + self.attrs.size()
+    }
+}
+
 impl Receivable for Name {
-    fn recv<R: io::Read>(r: &mut R) -> Result<Name> {
+    fn recv<R: Decode>(r: &mut R) -> Result<Name> {
         let filename = try!(Vec::<u8>::recv(r));
         let longname = try!(Vec::<u8>::recv(r));
         let attrs = try!(FileAttr::recv(r));
@@ -827,7 +1781,8 @@ impl Receivable for Name {
     }
 }
 
-#[derive(Debug)]
+/// SSH_FXP_NAME: directory entries from readdir, or the single name from realpath or readlink.
+#[derive(Debug, PartialEq)]
 pub struct FxpName {
     pub names: Vec<Name>,
 }
@@ -836,9 +1791,25 @@ impl Response for FxpName {
     fn msg_type() -> u8 { SSH_FXP_NAME }
 }
 
+impl Sendable for FxpName {
+    fn write_to<W: io::Write>(&self, w: &mut W) -> Result<()> {
+        try!((self.names.len() as u32).write_to(w));
+        for name in self.names.iter() {
+            try!(name.write_to(w));
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u32 {
+        4 + self.names.iter().fold(0, |acc, n| acc + n.size())
+    }
+}
+
 impl Receivable for FxpName {
-    fn recv<R: io::Read>(r: &mut R) -> Result<FxpName> {
+    fn recv<R: Decode>(r: &mut R) -> Result<FxpName> {
         let count = try!(u32::recv(r));
+        // Each name has two strings and the attribute flags.
+        try!(check_count(r, count, 12));
         let mut names = Vec::new();
         for _ in 0..count {
             names.push(try!(Name::recv(r)));
@@ -847,9 +1818,22 @@ impl Receivable for FxpName {
     }
 }
 
-pub fn recv<R: io::Read>(r: &mut R) -> Result<SftpResponse> {
-    let l = try!(u32::recv(r));
-    let mut lr = r.take(l as u64);
+pub fn recv<R>(r: &mut R, limits: &DecodeLimits) -> Result<SftpResponse> where R: io::Read {
+    match try!(recv_with_buffer(r, limits, |_| None)) {
+        (req_id, Received::Packet(packet)) => Ok(SftpResponse{req_id: req_id, packet: packet}),
+        (_, Received::DataInBuffer(_)) => unreachable!(),
+    }
+}
+
+// `data_buffer` is asked for a buffer to decode SSH_FXP_DATA into when one arrives for the given
+// request id; if it returns None the data is collected in an `FxpData` as usual.
+pub fn recv_with_buffer<'b, R, F>(r: &mut R, limits: &DecodeLimits, mut data_buffer: F) -> Result<(u32, Received)>
+    where R: io::Read, F: FnMut(u32) -> Option<&'b mut [u8]> {
+    let l = try!(r.read_u32::<BigEndian>());
+    if l > limits.max_packet_len {
+        return Err(Error::PacketTooLarge(l));
+    }
+    let mut lr = Frame{r: r.take(l as u64), max_string_len: limits.max_string_len};
     let msg_type = try!(u8::recv(&mut lr));
     // SSH_FXP_VERSION is the one response that is returned without a request id. Hardcode it to
     // zero.
@@ -858,26 +1842,324 @@ pub fn recv<R: io::Read>(r: &mut R) -> Result<SftpResponse> {
     } else {
         try!(u32::recv(&mut lr))
     };
-    let response = if msg_type == SSH_FXP_VERSION {
+    let packet = if msg_type == SSH_FXP_VERSION {
         SftpResponsePacket::Version(try!(FxpVersion::recv(&mut lr)))
     } else if msg_type == SSH_FXP_STATUS {
         SftpResponsePacket::Status(try!(FxpStatus::recv(&mut lr)))
     } else if msg_type == SSH_FXP_HANDLE {
         SftpResponsePacket::Handle(try!(FxpHandle::recv(&mut lr)))
     } else if msg_type == SSH_FXP_DATA {
-        SftpResponsePacket::Data(try!(FxpData::recv(&mut lr)))
+        match data_buffer(req_id) {
+            Some(buf) => {
+                let n = try!(FxpData::recv_into(&mut lr, buf));
+                if lr.remaining() > 0 {
+                    return Err(Error::UnexpectedData)
+                }
+                return Ok((req_id, Received::DataInBuffer(n)));
+            },
+            None => SftpResponsePacket::Data(try!(FxpData::recv(&mut lr))),
+        }
     } else if msg_type == SSH_FXP_NAME {
         SftpResponsePacket::Name(try!(FxpName::recv(&mut lr)))
     } else if msg_type == SSH_FXP_ATTRS {
         SftpResponsePacket::Attrs(try!(FileAttr::recv(&mut lr)))
+    } else if msg_type == SSH_FXP_EXTENDED_REPLY {
+        SftpResponsePacket::ExtendedReply(try!(FxpExtendedReply::recv(&mut lr)))
     } else {
         let mut data = Vec::new();
         try!(lr.read_to_end(&mut data));
         SftpResponsePacket::Unknown{msg_type: msg_type, data: data}
     };
-    if lr.limit() > 0 {
+    if lr.remaining() > 0 {
         return Err(Error::UnexpectedData)
     }
-    Ok(SftpResponse{req_id: req_id, packet: response})
+    Ok((req_id, Received::Packet(packet)))
 }
 
+// Server side counterpart of `recv`: reads one framed request.
+pub fn recv_request<R>(r: &mut R, limits: &DecodeLimits) -> Result<SftpRequest> where R: io::Read {
+    let l = try!(r.read_u32::<BigEndian>());
+    if l > limits.max_packet_len {
+        return Err(Error::PacketTooLarge(l));
+    }
+    let mut lr = Frame{r: r.take(l as u64), max_string_len: limits.max_string_len};
+    let msg_type = try!(u8::recv(&mut lr));
+    // Like SSH_FXP_VERSION, SSH_FXP_INIT carries no request id.
+    if msg_type == SSH_FXP_INIT {
+        let init = try!(FxpInit::recv(&mut lr));
+        return Ok(SftpRequest{req_id: 0, packet: SftpRequestPacket::Init(init)});
+    }
+    let req_id = try!(u32::recv(&mut lr));
+    let request = match msg_type {
+        SSH_FXP_OPEN => SftpRequestPacket::Open(try!(FxpOpen::recv(&mut lr))),
+        SSH_FXP_CLOSE => SftpRequestPacket::Close(try!(FxpClose::recv(&mut lr))),
+        SSH_FXP_READ => SftpRequestPacket::Read(try!(FxpRead::recv(&mut lr))),
+        SSH_FXP_WRITE => SftpRequestPacket::Write(try!(FxpWrite::recv(&mut lr))),
+        SSH_FXP_LSTAT => SftpRequestPacket::LStat(try!(FxpLStat::recv(&mut lr))),
+        SSH_FXP_FSTAT => SftpRequestPacket::FStat(try!(FxpFStat::recv(&mut lr))),
+        SSH_FXP_SETSTAT => SftpRequestPacket::SetStat(try!(FxpSetStat::recv(&mut lr))),
+        SSH_FXP_FSETSTAT => SftpRequestPacket::FSetStat(try!(FxpFSetStat::recv(&mut lr))),
+        SSH_FXP_OPENDIR => SftpRequestPacket::OpenDir(try!(FxpOpenDir::recv(&mut lr))),
+        SSH_FXP_READDIR => SftpRequestPacket::ReadDir(try!(FxpReadDir::recv(&mut lr))),
+        SSH_FXP_REMOVE => SftpRequestPacket::Remove(try!(FxpRemove::recv(&mut lr))),
+        SSH_FXP_MKDIR => SftpRequestPacket::MkDir(try!(FxpMkDir::recv(&mut lr))),
+        SSH_FXP_RMDIR => SftpRequestPacket::RmDir(try!(FxpRmDir::recv(&mut lr))),
+        SSH_FXP_REALPATH => SftpRequestPacket::RealPath(try!(FxpRealPath::recv(&mut lr))),
+        SSH_FXP_STAT => SftpRequestPacket::Stat(try!(FxpStat::recv(&mut lr))),
+        SSH_FXP_RENAME => SftpRequestPacket::Rename(try!(FxpRename::recv(&mut lr))),
+        SSH_FXP_READLINK => SftpRequestPacket::ReadLink(try!(FxpReadLink::recv(&mut lr))),
+        SSH_FXP_EXTENDED => try!(recv_extended_request(&mut lr)),
+        _ => {
+            let mut data = Vec::new();
+            try!(lr.read_to_end(&mut data));
+            SftpRequestPacket::Unknown{msg_type: msg_type, data: data}
+        },
+    };
+    if lr.remaining() > 0 {
+        return Err(Error::UnexpectedData)
+    }
+    Ok(SftpRequest{req_id: req_id, packet: request})
+}
+
+// The extended request decoders read the extension name themselves, so look at it without
+// consuming it and hand them the whole remainder of the frame.
+fn recv_extended_request<R: Decode>(r: &mut R) -> Result<SftpRequestPacket> {
+    let max_string_len = r.max_string_len();
+    let mut body = Vec::new();
+    try!(r.read_to_end(&mut body));
+    let name = try!(Vec::<u8>::recv(&mut io::Cursor::new(&body)));
+    let len = body.len() as u64;
+    let mut c = io::Cursor::new(body);
+    let mut r = Frame{r: (&mut c).take(len), max_string_len: max_string_len};
+    let request = match String::from_utf8_lossy(&name).as_ref() {
+        COPY_DATA => SftpRequestPacket::CopyData(try!(FxpCopyData::recv(&mut r))),
+        COPY_FILE => SftpRequestPacket::CopyFile(try!(FxpCopyFile::recv(&mut r))),
+        CHECK_FILE_NAME | CHECK_FILE_HANDLE => SftpRequestPacket::CheckFile(try!(FxpCheckFile::recv(&mut r))),
+        MD5_HASH | MD5_HASH_HANDLE => SftpRequestPacket::Md5Hash(try!(FxpMd5Hash::recv(&mut r))),
+        POSIX_RENAME => SftpRequestPacket::PosixRename(try!(FxpPosixRename::recv(&mut r))),
+        FSYNC => SftpRequestPacket::Fsync(try!(FxpFsync::recv(&mut r))),
+        LIMITS => SftpRequestPacket::Limits(try!(FxpLimits::recv(&mut r))),
+        _ => {
+            try!(Vec::<u8>::recv(&mut r));
+            let mut data = Vec::new();
+            try!(r.read_to_end(&mut data));
+            SftpRequestPacket::Extended{name: name, data: data}
+        },
+    };
+    if r.remaining() > 0 {
+        return Err(Error::UnexpectedData)
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::fmt;
+    use std::io;
+    use quickcheck::{Arbitrary, Gen};
+    use super::*;
+
+    impl Arbitrary for Extension {
+        fn arbitrary<G: Gen>(g: &mut G) -> Extension {
+            Extension{name: Vec::arbitrary(g), data: Vec::arbitrary(g)}
+        }
+    }
+
+    impl Arbitrary for FileAttr {
+        fn arbitrary<G: Gen>(g: &mut G) -> FileAttr {
+            // Half-set uid/gid and atime/mtime pairs included, see `Encodable` for attributes that
+            // can be sent.
+            FileAttr{
+                size: Option::arbitrary(g),
+                uid: Option::arbitrary(g),
+                gid: Option::arbitrary(g),
+                perms: Option::arbitrary(g),
+                atime: Option::arbitrary(g),
+                mtime: Option::arbitrary(g),
+                extensions: Vec::arbitrary(g),
+            }
+        }
+    }
+
+    fn half_set(attrs: &FileAttr) -> bool {
+        attrs.uid.is_some() != attrs.gid.is_some() || attrs.atime.is_some() != attrs.mtime.is_some()
+    }
+
+    // Attributes with uid/gid and atime/mtime either both set or both unset.
+    #[derive(Debug, Clone)]
+    struct Encodable(FileAttr);
+
+    impl Arbitrary for Encodable {
+        fn arbitrary<G: Gen>(g: &mut G) -> Encodable {
+            let mut attrs = FileAttr::arbitrary(g);
+            if attrs.uid.is_some() != attrs.gid.is_some() {
+                attrs.uid = None;
+                attrs.gid = None;
+            }
+            if attrs.atime.is_some() != attrs.mtime.is_some() {
+                attrs.atime = None;
+                attrs.mtime = None;
+            }
+            Encodable(attrs)
+        }
+    }
+
+    // Encodes `p`, checks the encoding is `size()` bytes long and decodes it back.
+    fn round_trip<T: Sendable + Receivable + PartialEq + fmt::Debug>(p: &T) -> bool {
+        let mut buf = Vec::new();
+        p.write_to(&mut buf).unwrap();
+        if buf.len() != p.size() as usize {
+            return false;
+        }
+        let mut r = io::Cursor::new(buf);
+        match T::recv(&mut r) {
+            Ok(ref decoded) if decoded == p => r.remaining() == 0,
+            _ => false,
+        }
+    }
+
+    fn names(names: Vec<(Vec<u8>, Vec<u8>, Encodable)>) -> Vec<Name> {
+        names.into_iter().map(|(f, l, a)| Name{filename: f, longname: l, attrs: a.0}).collect()
+    }
+
+    quickcheck! {
+        fn file_attr(attrs: FileAttr) -> bool {
+            if !half_set(&attrs) {
+                return round_trip(&attrs);
+            }
+            match attrs.write_to(&mut Vec::new()) {
+                Err(Error::IncompleteAttrs(_)) => true,
+                _ => false,
+            }
+        }
+
+        fn init(version: u32, extensions: Vec<Extension>) -> bool {
+            round_trip(&FxpInit{version: version, extensions: extensions})
+        }
+
+        fn open(filename: Vec<u8>, pflags: u32, attrs: Encodable) -> bool {
+            round_trip(&FxpOpen{filename: filename, pflags: pflags, attrs: attrs.0})
+        }
+
+        fn close(handle: Vec<u8>) -> bool {
+            round_trip(&FxpClose{handle: handle})
+        }
+
+        fn read(handle: Vec<u8>, offset: u64, len: u32) -> bool {
+            round_trip(&FxpRead{handle: handle, offset: offset, len: len})
+        }
+
+        fn write(handle: Vec<u8>, offset: u64, data: Vec<u8>) -> bool {
+            let p = FxpWrite{handle: Cow::Owned(handle), offset: offset, data: Cow::Owned(data)};
+            let mut head = Vec::new();
+            p.write_head_to(&mut head).unwrap();
+            head.extend_from_slice(p.trailing_data());
+            let mut full = Vec::new();
+            p.write_to(&mut full).unwrap();
+            head == full && round_trip(&p)
+        }
+
+        fn path_requests(path: Vec<u8>, attrs: Encodable) -> bool {
+            let attrs = attrs.0;
+            round_trip(&FxpLStat{path: path.clone()}) &&
+                round_trip(&FxpStat{path: path.clone()}) &&
+                round_trip(&FxpSetStat{path: path.clone(), attrs: attrs.clone()}) &&
+                round_trip(&FxpOpenDir{path: path.clone()}) &&
+                round_trip(&FxpRemove{filename: path.clone()}) &&
+                round_trip(&FxpMkDir{path: path.clone(), attrs: attrs}) &&
+                round_trip(&FxpRmDir{path: path.clone()}) &&
+                round_trip(&FxpRealPath{path: path.clone()}) &&
+                round_trip(&FxpReadLink{path: path})
+        }
+
+        fn handle_requests(handle: Vec<u8>, attrs: Encodable) -> bool {
+            let attrs = attrs.0;
+            round_trip(&FxpFStat{handle: handle.clone()}) &&
+                round_trip(&FxpFSetStat{handle: handle.clone(), attrs: attrs}) &&
+                round_trip(&FxpReadDir{handle: handle.clone()}) &&
+                round_trip(&FxpFsync{handle: handle})
+        }
+
+        fn rename(oldpath: Vec<u8>, newpath: Vec<u8>) -> bool {
+            round_trip(&FxpRename{oldpath: oldpath.clone(), newpath: newpath.clone()}) &&
+                round_trip(&FxpPosixRename{oldpath: oldpath, newpath: newpath})
+        }
+
+        fn copy_data(read_handle: Vec<u8>, read_offset: u64, read_len: u64, write_handle: Vec<u8>,
+                     write_offset: u64) -> bool {
+            round_trip(&FxpCopyData{
+                read_handle: read_handle,
+                read_offset: read_offset,
+                read_len: read_len,
+                write_handle: write_handle,
+                write_offset: write_offset,
+            })
+        }
+
+        fn copy_file(source: Vec<u8>, destination: Vec<u8>, overwrite: bool) -> bool {
+            round_trip(&FxpCopyFile{source: source, destination: destination, overwrite: overwrite})
+        }
+
+        fn check_file(by_handle: bool, name: Vec<u8>, hash_algorithms: Vec<u8>, start_offset: u64, length: u64,
+                      block_size: u32) -> bool {
+            round_trip(&FxpCheckFile{
+                extension: if by_handle { CHECK_FILE_HANDLE } else { CHECK_FILE_NAME },
+                name: name,
+                hash_algorithms: hash_algorithms,
+                start_offset: start_offset,
+                length: length,
+                block_size: block_size,
+            })
+        }
+
+        fn md5_hash(by_handle: bool, name: Vec<u8>, start_offset: u64, length: u64, quick_check_hash: Vec<u8>)
+                    -> bool {
+            round_trip(&FxpMd5Hash{
+                extension: if by_handle { MD5_HASH_HANDLE } else { MD5_HASH },
+                name: name,
+                start_offset: start_offset,
+                length: length,
+                quick_check_hash: quick_check_hash,
+            })
+        }
+
+        fn version(version: u32, extensions: Vec<Extension>) -> bool {
+            round_trip(&FxpVersion{version: version, extensions: extensions})
+        }
+
+        fn status(code: u32, msg: String) -> bool {
+            round_trip(&FxpStatus{code: FxpStatusCode::from_code(code), msg: msg})
+        }
+
+        fn handle(handle: Vec<u8>) -> bool {
+            round_trip(&FxpHandle{handle: handle})
+        }
+
+        fn data(data: Vec<u8>) -> bool {
+            round_trip(&FxpData{data: data})
+        }
+
+        fn name(entries: Vec<(Vec<u8>, Vec<u8>, Encodable)>) -> bool {
+            round_trip(&FxpName{names: names(entries)})
+        }
+
+        fn extended_reply(data: Vec<u8>) -> bool {
+            round_trip(&FxpExtendedReply{data: data})
+        }
+
+        fn limits(max_packet_len: u64, max_read_len: u64, max_write_len: u64, max_open_handles: u64) -> bool {
+            round_trip(&Limits{
+                max_packet_len: max_packet_len,
+                max_read_len: max_read_len,
+                max_write_len: max_write_len,
+                max_open_handles: max_open_handles,
+            })
+        }
+    }
+
+    #[test]
+    fn limits_request() {
+        assert!(round_trip(&FxpLimits));
+    }
+}
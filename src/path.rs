@@ -0,0 +1,371 @@
+use std::borrow::{Borrow, Cow, ToOwned};
+use std::fmt;
+use std::ops::Deref;
+use std::str;
+
+/// A slice of a remote path. Remote paths are plain bytes separated by `/`, independent of the
+/// conventions of the local platform and not required to be valid UTF-8.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RemotePath {
+    inner: [u8],
+}
+
+/// An owned, mutable remote path.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RemotePathBuf {
+    inner: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component<'a> {
+    RootDir,
+    CurDir,
+    ParentDir,
+    Normal(&'a [u8]),
+}
+
+impl<'a> Component<'a> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        match *self {
+            Component::RootDir => b"/",
+            Component::CurDir => b".",
+            Component::ParentDir => b"..",
+            Component::Normal(name) => name,
+        }
+    }
+}
+
+/// Iterator over the components of a `RemotePath`. Repeated separators and interior `.`
+/// components are skipped, as with `std::path::Components`.
+pub struct Components<'a> {
+    path: &'a [u8],
+    pos: usize,
+    started: bool,
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = Component<'a>;
+
+    fn next(&mut self) -> Option<Component<'a>> {
+        if !self.started {
+            self.started = true;
+            if self.path.starts_with(b"/") {
+                self.pos = 1;
+                return Some(Component::RootDir);
+            }
+            if self.path == b"." || self.path.starts_with(b"./") {
+                self.pos = 1;
+                return Some(Component::CurDir);
+            }
+        }
+        loop {
+            while self.pos < self.path.len() && self.path[self.pos] == b'/' {
+                self.pos += 1;
+            }
+            if self.pos >= self.path.len() {
+                return None;
+            }
+            let start = self.pos;
+            while self.pos < self.path.len() && self.path[self.pos] != b'/' {
+                self.pos += 1;
+            }
+            match &self.path[start..self.pos] {
+                b"." => continue,
+                b".." => return Some(Component::ParentDir),
+                name => return Some(Component::Normal(name)),
+            }
+        }
+    }
+}
+
+impl RemotePath {
+    pub fn new<S>(s: &S) -> &RemotePath where S: AsRef<[u8]> + ?Sized {
+        unsafe { &*(s.as_ref() as *const [u8] as *const RemotePath) }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.inner
+    }
+
+    pub fn to_str(&self) -> Option<&str> {
+        str::from_utf8(&self.inner).ok()
+    }
+
+    pub fn to_string_lossy(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.inner)
+    }
+
+    pub fn to_remote_path_buf(&self) -> RemotePathBuf {
+        RemotePathBuf{inner: self.inner.to_vec()}
+    }
+
+    pub fn display(&self) -> Display {
+        Display{path: self}
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.inner.starts_with(b"/")
+    }
+
+    pub fn components(&self) -> Components {
+        Components{path: &self.inner, pos: 0, started: false}
+    }
+
+    // The path without trailing separators, except that the root stays as is.
+    fn trimmed(&self) -> &[u8] {
+        let mut end = self.inner.len();
+        while end > 1 && self.inner[end - 1] == b'/' {
+            end -= 1;
+        }
+        &self.inner[..end]
+    }
+
+    /// The path without its final component, or None if it is the root or empty.
+    pub fn parent(&self) -> Option<&RemotePath> {
+        let path = self.trimmed();
+        if path.is_empty() || path == b"/" {
+            return None;
+        }
+        match path.iter().rposition(|&b| b == b'/') {
+            None => Some(RemotePath::new(&path[..0])),
+            Some(i) => {
+                let mut end = i;
+                while end > 0 && path[end - 1] == b'/' {
+                    end -= 1;
+                }
+                if end == 0 {
+                    Some(RemotePath::new(&path[..1]))
+                } else {
+                    Some(RemotePath::new(&path[..end]))
+                }
+            },
+        }
+    }
+
+    pub fn file_name(&self) -> Option<&[u8]> {
+        let path = self.trimmed();
+        let name = match path.iter().rposition(|&b| b == b'/') {
+            Some(i) => &path[i + 1..],
+            None => path,
+        };
+        match name {
+            b"" | b"." | b".." => None,
+            name => Some(name),
+        }
+    }
+
+    pub fn file_stem(&self) -> Option<&[u8]> {
+        self.file_name().map(|name| {
+            match name.iter().rposition(|&b| b == b'.') {
+                Some(0) | None => name,
+                Some(i) => &name[..i],
+            }
+        })
+    }
+
+    pub fn extension(&self) -> Option<&[u8]> {
+        self.file_name().and_then(|name| {
+            match name.iter().rposition(|&b| b == b'.') {
+                Some(0) | None => None,
+                Some(i) => Some(&name[i + 1..]),
+            }
+        })
+    }
+
+    /// Appends `path` to this one. If `path` is absolute it replaces this path entirely.
+    pub fn join<P>(&self, path: P) -> RemotePathBuf where P: AsRef<RemotePath> {
+        let mut buf = self.to_remote_path_buf();
+        buf.push(path);
+        buf
+    }
+
+    /// Lexically removes `.` components, repeated separators and `name/..` pairs. Leading `..`
+    /// components of relative paths are kept; `..` directly under the root is dropped. Symlinks
+    /// are not taken into account, so this can differ from what the server's realpath returns.
+    pub fn normalize(&self) -> RemotePathBuf {
+        let mut parts : Vec<Component> = Vec::new();
+        for component in self.components() {
+            match component {
+                Component::CurDir => {},
+                Component::ParentDir => match parts.last().cloned() {
+                    Some(Component::Normal(_)) => { parts.pop(); },
+                    Some(Component::RootDir) => {},
+                    _ => parts.push(component),
+                },
+                _ => parts.push(component),
+            }
+        }
+        let mut buf = RemotePathBuf::new();
+        for part in parts {
+            buf.push(RemotePath::new(part.as_bytes()));
+        }
+        if buf.inner.is_empty() {
+            buf.inner.push(b'.');
+        }
+        buf
+    }
+}
+
+impl RemotePathBuf {
+    pub fn new() -> RemotePathBuf {
+        RemotePathBuf{inner: Vec::new()}
+    }
+
+    pub fn as_remote_path(&self) -> &RemotePath {
+        RemotePath::new(&self.inner)
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.inner
+    }
+
+    pub fn push<P>(&mut self, path: P) where P: AsRef<RemotePath> {
+        let path = path.as_ref();
+        if path.is_absolute() {
+            self.inner.clear();
+        } else if !self.inner.is_empty() && !self.inner.ends_with(b"/") {
+            self.inner.push(b'/');
+        }
+        self.inner.extend(path.as_bytes().iter().cloned());
+    }
+
+    /// Truncates to the parent. Returns false, leaving the path alone, if there is no parent.
+    pub fn pop(&mut self) -> bool {
+        let len = match self.parent() {
+            Some(parent) => parent.as_bytes().len(),
+            None => return false,
+        };
+        self.inner.truncate(len);
+        true
+    }
+
+    pub fn set_file_name<S>(&mut self, file_name: &S) where S: AsRef<[u8]> + ?Sized {
+        if self.file_name().is_some() {
+            self.pop();
+        }
+        self.push(RemotePath::new(file_name));
+    }
+}
+
+impl Deref for RemotePathBuf {
+    type Target = RemotePath;
+
+    fn deref(&self) -> &RemotePath {
+        self.as_remote_path()
+    }
+}
+
+impl Borrow<RemotePath> for RemotePathBuf {
+    fn borrow(&self) -> &RemotePath {
+        self.as_remote_path()
+    }
+}
+
+impl ToOwned for RemotePath {
+    type Owned = RemotePathBuf;
+
+    fn to_owned(&self) -> RemotePathBuf {
+        self.to_remote_path_buf()
+    }
+}
+
+impl AsRef<RemotePath> for RemotePath {
+    fn as_ref(&self) -> &RemotePath {
+        self
+    }
+}
+
+impl AsRef<RemotePath> for RemotePathBuf {
+    fn as_ref(&self) -> &RemotePath {
+        self.as_remote_path()
+    }
+}
+
+impl AsRef<RemotePath> for str {
+    fn as_ref(&self) -> &RemotePath {
+        RemotePath::new(self)
+    }
+}
+
+impl AsRef<RemotePath> for String {
+    fn as_ref(&self) -> &RemotePath {
+        RemotePath::new(self)
+    }
+}
+
+impl AsRef<RemotePath> for [u8] {
+    fn as_ref(&self) -> &RemotePath {
+        RemotePath::new(self)
+    }
+}
+
+impl AsRef<RemotePath> for Vec<u8> {
+    fn as_ref(&self) -> &RemotePath {
+        RemotePath::new(self)
+    }
+}
+
+impl AsRef<[u8]> for RemotePath {
+    fn as_ref(&self) -> &[u8] {
+        &self.inner
+    }
+}
+
+impl AsRef<[u8]> for RemotePathBuf {
+    fn as_ref(&self) -> &[u8] {
+        &self.inner
+    }
+}
+
+impl<'a> From<&'a RemotePath> for RemotePathBuf {
+    fn from(path: &'a RemotePath) -> RemotePathBuf {
+        path.to_remote_path_buf()
+    }
+}
+
+impl<'a> From<&'a str> for RemotePathBuf {
+    fn from(s: &'a str) -> RemotePathBuf {
+        RemotePathBuf{inner: s.as_bytes().to_vec()}
+    }
+}
+
+impl From<String> for RemotePathBuf {
+    fn from(s: String) -> RemotePathBuf {
+        RemotePathBuf{inner: s.into_bytes()}
+    }
+}
+
+impl From<Vec<u8>> for RemotePathBuf {
+    fn from(bytes: Vec<u8>) -> RemotePathBuf {
+        RemotePathBuf{inner: bytes}
+    }
+}
+
+impl From<RemotePathBuf> for Vec<u8> {
+    fn from(path: RemotePathBuf) -> Vec<u8> {
+        path.inner
+    }
+}
+
+impl fmt::Debug for RemotePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.to_string_lossy(), f)
+    }
+}
+
+impl fmt::Debug for RemotePathBuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_remote_path(), f)
+    }
+}
+
+/// Displays a path, replacing invalid UTF-8 sequences.
+pub struct Display<'a> {
+    path: &'a RemotePath,
+}
+
+impl<'a> fmt::Display for Display<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.path.to_string_lossy(), f)
+    }
+}
@@ -0,0 +1,163 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use File;
+
+/// A snapshot of a single transfer.
+#[derive(Debug, Clone)]
+pub struct TransferStatus {
+    pub path: String,
+    pub transferred: u64,
+    pub total: Option<u64>,
+    pub elapsed: Duration,
+}
+
+impl TransferStatus {
+    /// Average rate in bytes per second since the transfer started.
+    pub fn rate(&self) -> f64 {
+        let secs = self.elapsed.as_secs() as f64 + self.elapsed.subsec_nanos() as f64 / 1e9;
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.transferred as f64 / secs
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        let total = match self.total {
+            Some(total) => total,
+            None => return None,
+        };
+        let rate = self.rate();
+        if rate == 0.0 {
+            return None;
+        }
+        let left = total.saturating_sub(self.transferred) as f64 / rate;
+        Some(Duration::new(left as u64, (left.fract() * 1e9) as u32))
+    }
+}
+
+/// Receives progress events from transfers. Callbacks may be invoked from several threads at once
+/// when a transfer is split across handles.
+pub trait Progress : Send + Sync {
+    fn started(&self, _path: &str, _total: Option<u64>) {}
+
+    fn update(&self, status: &TransferStatus);
+
+    fn finished(&self, _status: &TransferStatus) {}
+}
+
+impl<F> Progress for F where F: Fn(&TransferStatus) + Send + Sync {
+    fn update(&self, status: &TransferStatus) {
+        self(status)
+    }
+}
+
+pub struct Tracker {
+    progress: Arc<Progress>,
+    path: String,
+    total: Option<u64>,
+    start: Instant,
+    transferred: Mutex<u64>,
+}
+
+impl Tracker {
+    pub fn new(progress: Arc<Progress>, path: String, total: Option<u64>) -> Tracker {
+        progress.started(&path, total);
+        Tracker{
+            progress: progress,
+            path: path,
+            total: total,
+            start: Instant::now(),
+            transferred: Mutex::new(0),
+        }
+    }
+
+    fn status(&self, transferred: u64) -> TransferStatus {
+        TransferStatus{
+            path: self.path.clone(),
+            transferred: transferred,
+            total: self.total,
+            elapsed: self.start.elapsed(),
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        let status = {
+            let mut transferred = self.transferred.lock().unwrap();
+            *transferred += n;
+            self.status(*transferred)
+        };
+        self.progress.update(&status);
+    }
+
+    // Used when a partially transferred range has to be sent again.
+    pub fn sub(&self, n: u64) {
+        let status = {
+            let mut transferred = self.transferred.lock().unwrap();
+            *transferred = transferred.saturating_sub(n);
+            self.status(*transferred)
+        };
+        self.progress.update(&status);
+    }
+
+    pub fn finish(&self) {
+        let status = self.status(*self.transferred.lock().unwrap());
+        self.progress.finished(&status);
+    }
+}
+
+/// Wraps a reader or writer and reports every byte that passes through it, so that `io::copy`
+/// into or out of a remote `File` can drive a progress bar.
+pub struct WithProgress<T> {
+    inner: T,
+    tracker: Tracker,
+}
+
+impl<T> WithProgress<T> {
+    pub fn new<S>(inner: T, path: S, total: Option<u64>, progress: Arc<Progress>) -> WithProgress<T> where S: Into<String> {
+        WithProgress{inner: inner, tracker: Tracker::new(progress, path.into(), total)}
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<W> WithProgress<File<W>> where W : 'static + io::Write + Send {
+    /// Wraps a remote file, taking the total size from `File::stat`.
+    pub fn file<S>(mut file: File<W>, path: S, progress: Arc<Progress>) -> ::error::Result<WithProgress<File<W>>> where S: Into<String> {
+        let total = try!(file.stat()).size;
+        Ok(WithProgress::new(file, path, total, progress))
+    }
+}
+
+impl<T> Drop for WithProgress<T> {
+    fn drop(&mut self) {
+        self.tracker.finish();
+    }
+}
+
+impl<T> io::Read for WithProgress<T> where T: io::Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.tracker.add(n as u64);
+        Ok(n)
+    }
+}
+
+impl<T> io::Write for WithProgress<T> where T: io::Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.tracker.add(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
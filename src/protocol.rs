@@ -0,0 +1,150 @@
+//! The SFTP version 3 wire format, for building servers, proxies and analyzers on the same codec
+//! as `Client`.
+//!
+//! Every request and response has a struct implementing `Sendable` (encoding) and `Receivable`
+//! (decoding) for its body. `PacketWriter` and `PacketReader` add the framing around the body:
+//! the length, the message type and the request id.
+
+use std::io;
+
+use error::Result;
+use packets;
+
+pub use packets::{Sendable, Receivable, Request, Response, Decode, DecodeLimits};
+pub use packets::{SftpRequest, SftpRequestPacket, SftpResponse, SftpResponsePacket};
+pub use packets::{Extension, FileAttr, Limits};
+pub use packets::{FxpInit, FxpOpen, FxpClose, FxpRead, FxpWrite, FxpLStat, FxpFStat, FxpSetStat, FxpFSetStat,
+                  FxpOpenDir, FxpReadDir, FxpRemove, FxpMkDir, FxpRmDir, FxpRealPath, FxpStat, FxpRename,
+                  FxpReadLink, FxpCopyData, FxpCopyFile, FxpCheckFile, FxpMd5Hash, FxpPosixRename, FxpFsync,
+                  FxpLimits};
+pub use packets::{FxpVersion, FxpStatus, FxpStatusCode, FxpHandle, FxpData, FxpName, Name, FxpExtendedReply};
+
+pub use packets::{SSH_FXP_INIT, SSH_FXP_VERSION, SSH_FXP_OPEN, SSH_FXP_CLOSE, SSH_FXP_READ, SSH_FXP_WRITE,
+                  SSH_FXP_LSTAT, SSH_FXP_FSTAT, SSH_FXP_SETSTAT, SSH_FXP_FSETSTAT, SSH_FXP_OPENDIR,
+                  SSH_FXP_READDIR, SSH_FXP_REMOVE, SSH_FXP_MKDIR, SSH_FXP_RMDIR, SSH_FXP_REALPATH, SSH_FXP_STAT,
+                  SSH_FXP_RENAME, SSH_FXP_READLINK, SSH_FXP_STATUS, SSH_FXP_HANDLE, SSH_FXP_DATA, SSH_FXP_NAME,
+                  SSH_FXP_ATTRS, SSH_FXP_EXTENDED, SSH_FXP_EXTENDED_REPLY};
+pub use packets::{SSH_FX_OK, SSH_FX_EOF, SSH_FX_NO_SUCH_FILE, SSH_FX_PERMISSION_DENIED, SSH_FX_FAILURE,
+                  SSH_FX_BAD_MESSAGE, SSH_FX_NO_CONNECTION, SSH_FX_CONNECTION_LOST, SSH_FX_OP_UNSUPPORTED};
+pub use packets::{SSH_FILEXFER_ATTR_SIZE, SSH_FILEXFER_ATTR_UIDGID, SSH_FILEXFER_ATTR_PERMISSIONS,
+                  SSH_FILEXFER_ATTR_ACMODTIME, SSH_FILEXFER_ATTR_EXTENDED};
+pub use packets::{SSH_FXF_READ, SSH_FXF_WRITE, SSH_FXF_APPEND, SSH_FXF_CREAT, SSH_FXF_TRUNC, SSH_FXF_EXCL};
+pub use packets::{COPY_DATA, COPY_FILE, CHECK_FILE_NAME, CHECK_FILE_HANDLE, MD5_HASH, MD5_HASH_HANDLE,
+                  POSIX_RENAME, FSYNC, LIMITS};
+
+/// Reads framed packets. Frames and strings larger than the limits are rejected before anything
+/// is allocated for them.
+pub struct PacketReader<R> {
+    r: R,
+    limits: DecodeLimits,
+}
+
+impl<R> PacketReader<R> where R: io::Read {
+    pub fn new(r: R) -> PacketReader<R> {
+        PacketReader::with_limits(r, DecodeLimits::default())
+    }
+
+    pub fn with_limits(r: R, limits: DecodeLimits) -> PacketReader<R> {
+        PacketReader{r: r, limits: limits}
+    }
+
+    /// Reads the next packet sent by a client. SSH_FXP_INIT is returned with a request id of 0.
+    pub fn read_request(&mut self) -> Result<SftpRequest> {
+        packets::recv_request(&mut self.r, &self.limits)
+    }
+
+    /// Reads the next packet sent by a server. SSH_FXP_VERSION is returned with a request id of 0.
+    pub fn read_response(&mut self) -> Result<SftpResponse> {
+        packets::recv(&mut self.r, &self.limits)
+    }
+
+    pub fn limits(&self) -> DecodeLimits {
+        self.limits
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.r
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.r
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+}
+
+/// Writes framed packets. Each packet goes to the underlying writer with a single `write_all`,
+/// apart from the data of `FxpWrite`, which follows in a second one without being copied.
+pub struct PacketWriter<W> {
+    w: W,
+    buf: Vec<u8>,
+}
+
+impl<W> PacketWriter<W> where W: io::Write {
+    pub fn new(w: W) -> PacketWriter<W> {
+        PacketWriter{w: w, buf: Vec::new()}
+    }
+
+    pub fn write_init(&mut self, init: &FxpInit) -> Result<()> {
+        self.write_request_packet(None, init)
+    }
+
+    pub fn write_request<P: Request>(&mut self, req_id: u32, packet: &P) -> Result<()> {
+        self.write_request_packet(Some(req_id), packet)
+    }
+
+    pub fn write_version(&mut self, version: &FxpVersion) -> Result<()> {
+        self.write_response_packet(None, version)
+    }
+
+    pub fn write_response<P: Response + Sendable>(&mut self, req_id: u32, packet: &P) -> Result<()> {
+        self.write_response_packet(Some(req_id), packet)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(try!(self.w.flush()))
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.w
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.w
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
+    fn write_request_packet<P: Request>(&mut self, req_id: Option<u32>, packet: &P) -> Result<()> {
+        self.buf.clear();
+        try!(write_frame_head(&mut self.buf, P::msg_type(), req_id, packet.size()));
+        try!(packet.write_head_to(&mut self.buf));
+        try!(self.w.write_all(&self.buf));
+        let trailing = packet.trailing_data();
+        if !trailing.is_empty() {
+            try!(self.w.write_all(trailing));
+        }
+        Ok(())
+    }
+
+    fn write_response_packet<P: Response + Sendable>(&mut self, req_id: Option<u32>, packet: &P) -> Result<()> {
+        self.buf.clear();
+        try!(write_frame_head(&mut self.buf, P::msg_type(), req_id, packet.size()));
+        try!(packet.write_to(&mut self.buf));
+        Ok(try!(self.w.write_all(&self.buf)))
+    }
+}
+
+fn write_frame_head(buf: &mut Vec<u8>, msg_type: u8, req_id: Option<u32>, body_len: u32) -> Result<()> {
+    let n = msg_type.size() + req_id.map_or(0, |id| id.size()) + body_len;
+    try!(n.write_to(buf));
+    try!(msg_type.write_to(buf));
+    if let Some(req_id) = req_id {
+        try!(req_id.write_to(buf));
+    }
+    Ok(())
+}
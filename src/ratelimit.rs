@@ -0,0 +1,150 @@
+use std::cmp;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Upper bound on how long a waiter sleeps before re-checking the bucket, so that a rate change
+// made from another thread takes effect promptly.
+const MAX_SLEEP_MS : u64 = 100;
+
+/// The time source of a `RateLimiter`. Tests can substitute one that advances on `sleep` instead of
+/// actually sleeping.
+pub trait Clock : Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real time, used by `RateLimiter::new`.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+struct Bucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+    clock: Arc<Clock>,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.duration_since(self.last);
+        self.last = now;
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.tokens = (self.tokens + secs * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+/// A token bucket limiting throughput to a number of bytes per second. Clones share the same
+/// bucket, so one limiter can be applied to several clients or transfers at once and adjusted
+/// from any thread while they are running. A rate of zero means unlimited.
+#[derive(Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter::with_clock(bytes_per_sec, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(bytes_per_sec: u64, clock: Arc<Clock>) -> RateLimiter {
+        RateLimiter{bucket: Arc::new(Mutex::new(Bucket{
+            rate: bytes_per_sec,
+            tokens: bytes_per_sec as f64,
+            last: clock.now(),
+            clock: clock,
+        }))}
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, bytes_per_sec: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.rate = bytes_per_sec;
+        bucket.tokens = bucket.tokens.min(bytes_per_sec as f64);
+    }
+
+    /// Blocks until `n` bytes may be transferred.
+    pub fn acquire(&self, n: u64) {
+        let mut needed = n;
+        while needed > 0 {
+            let (wait, clock) = {
+                let mut bucket = self.bucket.lock().unwrap();
+                if bucket.rate == 0 {
+                    return;
+                }
+                bucket.refill();
+                // Requests larger than the bucket are let through a bucketful at a time.
+                let take = cmp::min(needed, bucket.rate) as f64;
+                if bucket.tokens >= take {
+                    bucket.tokens -= take;
+                    needed -= take as u64;
+                    continue;
+                }
+                ((take - bucket.tokens) / bucket.rate as f64, bucket.clock.clone())
+            };
+            let ms = cmp::min((wait * 1000.0).ceil() as u64, MAX_SLEEP_MS);
+            clock.sleep(Duration::from_millis(cmp::max(ms, 1)));
+        }
+    }
+}
+
+/// Wraps a reader or writer so that everything passing through it is throttled by a
+/// `RateLimiter`.
+pub struct Throttled<T> {
+    inner: T,
+    limiter: RateLimiter,
+}
+
+impl<T> Throttled<T> {
+    pub fn new(inner: T, limiter: RateLimiter) -> Throttled<T> {
+        Throttled{inner: inner, limiter: limiter}
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T> io::Read for Throttled<T> where T: io::Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.limiter.acquire(n as u64);
+        Ok(n)
+    }
+}
+
+impl<T> io::Write for Throttled<T> where T: io::Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.limiter.acquire(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,185 @@
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use checksum;
+use checksum::{HashAlgorithm, Hasher};
+use error::{Error, Result};
+use packets;
+use packets::FileAttr;
+use path::{RemotePath, RemotePathBuf};
+use transfer;
+use {Client, File, SSH_FXF_WRITE, SSH_FXF_CREAT, SSH_FXF_EXCL};
+
+const WRITE_BUFFER_SIZE : usize = 512 * 1024;
+
+static TEMP_COUNTER : AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Replaces a remote file so that readers see either the old or the new contents, never a
+/// partial file. The data goes to a temporary file in the same directory, which is then renamed
+/// over the target.
+///
+/// The rename is only atomic when the server supports posix-rename@openssh.com. Otherwise an
+/// existing target has to be removed first, so for a moment the path does not exist at all.
+pub struct AtomicWrite<'a, W> where W: 'a {
+    client: &'a mut Client<W>,
+    fsync: bool,
+    mode: Option<u32>,
+    preserve_permissions: bool,
+    verify: Option<HashAlgorithm>,
+}
+
+impl<'a, W> AtomicWrite<'a, W> where W : 'static + io::Write + Send {
+    pub fn new(client: &'a mut Client<W>) -> AtomicWrite<'a, W> {
+        let verify = *client.sender.verify.lock().unwrap();
+        AtomicWrite{
+            client: client,
+            fsync: false,
+            mode: None,
+            preserve_permissions: true,
+            verify: verify,
+        }
+    }
+
+    /// Flushes the data to disk before the rename, if the server supports fsync@openssh.com.
+    pub fn fsync(&mut self, fsync: bool) -> &mut AtomicWrite<'a, W> {
+        self.fsync = fsync;
+        self
+    }
+
+    /// The permissions of the new file. Overrides `preserve_permissions`.
+    pub fn mode(&mut self, mode: u32) -> &mut AtomicWrite<'a, W> {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Gives the new file the permissions of the one it replaces. On by default.
+    pub fn preserve_permissions(&mut self, preserve: bool) -> &mut AtomicWrite<'a, W> {
+        self.preserve_permissions = preserve;
+        self
+    }
+
+    /// Before the rename, compares a checksum of the temporary file against one of the contents,
+    /// so a corrupted upload never replaces the target. Defaults to `Client::set_verify`.
+    pub fn verify(&mut self, algorithm: HashAlgorithm) -> &mut AtomicWrite<'a, W> {
+        self.verify = Some(algorithm);
+        self
+    }
+
+    pub fn write<P, C>(&mut self, path: P, contents: C) -> Result<()> where P: AsRef<RemotePath>, C: AsRef<[u8]> {
+        let contents = contents.as_ref();
+        let sender = self.client.sender.clone();
+        let mut hasher = self.verify.map(Hasher::new);
+        sender.track(path.as_ref(), Some(contents.len() as u64), |tracker| {
+            self.replace(path.as_ref(), |file| {
+                try!(transfer::write_all_at(file, 0, contents, tracker));
+                if let Some(ref mut hasher) = hasher {
+                    hasher.input(contents);
+                }
+                Ok(((), hasher.map(Hasher::result)))
+            })
+        })
+    }
+
+    /// Like `write`, but takes the contents from a reader. Returns the number of bytes written.
+    pub fn write_from<P, R>(&mut self, path: P, reader: &mut R) -> Result<u64> where P: AsRef<RemotePath>, R: io::Read {
+        let sender = self.client.sender.clone();
+        let mut hasher = self.verify.map(Hasher::new);
+        sender.track(path.as_ref(), None, |tracker| self.replace(path.as_ref(), |file| {
+            let mut buf = vec![0; WRITE_BUFFER_SIZE];
+            let mut offset = 0;
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(0) => return Ok((offset, hasher.map(Hasher::result))),
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(Error::Io(e)),
+                };
+                try!(transfer::write_all_at(file, offset, &buf[..n], tracker));
+                if let Some(ref mut hasher) = hasher {
+                    hasher.input(&buf[..n]);
+                }
+                offset += n as u64;
+            }
+        }))
+    }
+
+    // `fill` writes the temporary file and returns its result along with the checksum the file
+    // should have, when it is to be verified.
+    fn replace<T, F>(&mut self, path: &RemotePath, fill: F) -> Result<T>
+        where F: FnOnce(&File<W>) -> Result<(T, Option<Vec<u8>>)> {
+        let temp = try!(temp_path(path));
+        let mut attrs = FileAttr::new();
+        attrs.perms = match self.mode {
+            Some(mode) => Some(mode),
+            None if self.preserve_permissions => self.client.metadata(path).ok().map(|m| m.permissions().mode()),
+            None => None,
+        };
+        let encoded = try!(self.client.sender.encode_path(&temp));
+        let file = try!(File::open(self.client.sender.clone(), encoded, SSH_FXF_WRITE | SSH_FXF_CREAT | SSH_FXF_EXCL,
+                                   attrs.clone()));
+        let result = self.finish(file, &temp, path, attrs, fill);
+        if result.is_err() {
+            let _ = self.client.remove(&temp);
+        }
+        result
+    }
+
+    fn finish<T, F>(&mut self, mut file: File<W>, temp: &RemotePath, path: &RemotePath, attrs: FileAttr,
+                    fill: F) -> Result<T> where F: FnOnce(&File<W>) -> Result<(T, Option<Vec<u8>>)> {
+        let (value, expected) = try!(fill(&file));
+        // The mode given at open time is subject to the server's umask.
+        if attrs.perms.is_some() {
+            try!(file.setstat(attrs));
+        }
+        if self.fsync && self.client.sender.has_extension(packets::FSYNC) {
+            let resp = try!(self.client.sender.send_receive(&packets::FxpFsync{handle: file.handle.clone()}));
+            try!(Client::<W>::expect_status_response(resp));
+        }
+        drop(file);
+        if let (Some(algorithm), Some(expected)) = (self.verify, expected) {
+            try!(checksum::verify_path(&self.client.sender, temp, algorithm, None, &expected));
+        }
+        try!(self.rename_over(temp, path));
+        Ok(value)
+    }
+
+    fn rename_over(&mut self, temp: &RemotePath, path: &RemotePath) -> Result<()> {
+        if self.client.sender.has_extension(packets::POSIX_RENAME) {
+            let p = packets::FxpPosixRename{
+                oldpath: try!(self.client.sender.encode_path(temp)),
+                newpath: try!(self.client.sender.encode_path(path)),
+            };
+            let resp = try!(self.client.sender.send_receive(&p));
+            return Client::<W>::expect_status_response(resp);
+        }
+        // Plain SSH_FXP_RENAME refuses to replace an existing file.
+        match self.client.rename(temp, path) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if self.client.symlink_metadata(path).is_err() {
+                    return Err(e);
+                }
+                try!(self.client.remove(path));
+                self.client.rename(temp, path)
+            },
+        }
+    }
+}
+
+// A hidden name next to `path`. Other clients may be writing the same directory, so the process id
+// and counter are mixed with the time; the file is opened exclusively in any case.
+fn temp_path(path: &RemotePath) -> Result<RemotePathBuf> {
+    let name = match path.file_name() {
+        Some(name) => name,
+        None => return Err(Error::InvalidFilename(path.as_bytes().to_vec())),
+    };
+    let mut temp_name = b".".to_vec();
+    temp_name.extend(name.iter().cloned());
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    temp_name.extend(format!(".{}.{}.{}.tmp", process::id(), nanos, TEMP_COUNTER.fetch_add(1, Ordering::SeqCst)).bytes());
+    let mut temp = path.to_remote_path_buf();
+    temp.set_file_name(&temp_name);
+    Ok(temp)
+}
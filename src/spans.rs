@@ -0,0 +1,53 @@
+// A `tracing` span for each request, open from when the request is sent until its response
+// arrives. It carries the operation, request id and path, and gets the latency and outcome once
+// they are known. Without the `tracing` feature it is empty and compiles to nothing.
+
+use std::time::Duration;
+
+#[cfg(feature = "tracing")]
+use tracing::{field, Span};
+
+#[cfg(feature = "tracing")]
+#[derive(Clone)]
+pub struct RequestSpan(Span);
+
+#[cfg(feature = "tracing")]
+impl RequestSpan {
+    pub fn new(op: &'static str, req_id: u32, path: Option<&[u8]>) -> RequestSpan {
+        let span = debug_span!("sftp_request", op = op, req_id = req_id, path = field::Empty,
+                               latency_us = field::Empty, status = field::Empty);
+        if let Some(path) = path {
+            span.record("path", &&*String::from_utf8_lossy(path));
+        }
+        RequestSpan(span)
+    }
+
+    // `status` is the status code's name for SSH_FXP_STATUS and the message type otherwise.
+    pub fn finish(&self, latency: Duration, status: &'static str) {
+        let micros = latency.as_secs() * 1_000_000 + (latency.subsec_nanos() / 1_000) as u64;
+        self.0.record("latency_us", &micros);
+        self.0.record("status", &status);
+    }
+
+    // Runs `f`, typically logging something, inside the span.
+    pub fn in_scope<F: FnOnce()>(&self, f: F) {
+        self.0.in_scope(f)
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub struct RequestSpan;
+
+#[cfg(not(feature = "tracing"))]
+impl RequestSpan {
+    pub fn new(_op: &'static str, _req_id: u32, _path: Option<&[u8]>) -> RequestSpan {
+        RequestSpan
+    }
+
+    pub fn finish(&self, _latency: Duration, _status: &'static str) {}
+
+    pub fn in_scope<F: FnOnce()>(&self, f: F) {
+        f()
+    }
+}
@@ -0,0 +1,348 @@
+extern crate byteorder;
+extern crate rustc_serialize;
+
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+use std::u32;
+
+use self::byteorder::{BigEndian, ByteOrder};
+use self::rustc_serialize::hex::{FromHex, ToHex};
+use self::rustc_serialize::json::Json;
+
+use error::{Error, Result};
+use packets;
+use Client;
+
+/// How `Tracer` writes its records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    /// One line per packet: seconds since the start, direction, request id and the decoded packet.
+    Text,
+    /// One JSON object per line with the fields `time`, `dir` (`send` or `recv`), `req_id`,
+    /// `packet` and `raw`, the hex encoded frame. This is the format `Replay` reads.
+    JsonLines,
+}
+
+struct TraceLog {
+    out: Box<io::Write + Send>,
+    format: TraceFormat,
+    start: Instant,
+}
+
+impl TraceLog {
+    fn record(&mut self, dir: Direction, frame: &[u8]) {
+        let elapsed = self.start.elapsed();
+        let time = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let (req_id, packet) = match dir {
+            Direction::Send => describe_request(frame),
+            Direction::Recv => describe_response(frame),
+        };
+        // A failing trace sink must not break the connection being traced.
+        let _ = match self.format {
+            TraceFormat::Text => {
+                let arrow = if dir == Direction::Send { "->" } else { "<-" };
+                writeln!(self.out, "{:12.6} {} {:>6} {}", time, arrow, req_id.map_or("-".to_string(), |id| id.to_string()), packet)
+            },
+            TraceFormat::JsonLines => {
+                let mut obj = BTreeMap::new();
+                obj.insert("time".to_string(), Json::F64(time));
+                obj.insert("dir".to_string(), Json::String(dir.name().to_string()));
+                obj.insert("req_id".to_string(), req_id.map_or(Json::Null, |id| Json::U64(id as u64)));
+                obj.insert("packet".to_string(), Json::String(packet));
+                obj.insert("raw".to_string(), Json::String(frame.to_hex()));
+                writeln!(self.out, "{}", Json::Object(obj))
+            },
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Direction {
+    Send,
+    Recv,
+}
+
+impl Direction {
+    fn name(&self) -> &'static str {
+        match *self {
+            Direction::Send => "send",
+            Direction::Recv => "recv",
+        }
+    }
+}
+
+// The frame is already in memory, so the only limit that matters is its own length.
+fn trace_limits() -> packets::DecodeLimits {
+    packets::DecodeLimits{max_packet_len: u32::MAX, max_string_len: u32::MAX}
+}
+
+fn describe_request(frame: &[u8]) -> (Option<u32>, String) {
+    match packets::recv_request(&mut io::Cursor::new(frame), &trace_limits()) {
+        Ok(packets::SftpRequest{packet: packets::SftpRequestPacket::Init(init), ..}) => (None, format!("{:?}", init)),
+        // File contents would drown everything else.
+        Ok(packets::SftpRequest{req_id, packet: packets::SftpRequestPacket::Write(w)}) =>
+            (Some(req_id), format!("FxpWrite {{ handle: {:?}, offset: {}, len: {} }}", w.handle, w.offset, w.data.len())),
+        Ok(req) => (Some(req.req_id), format!("{:?}", req.packet)),
+        Err(e) => (None, format!("undecodable request: {}", e)),
+    }
+}
+
+fn describe_response(frame: &[u8]) -> (Option<u32>, String) {
+    match packets::recv(&mut io::Cursor::new(frame), &trace_limits()) {
+        Ok(packets::SftpResponse{packet: packets::SftpResponsePacket::Version(v), ..}) => (None, format!("{:?}", v)),
+        Ok(packets::SftpResponse{req_id, packet: packets::SftpResponsePacket::Data(d)}) =>
+            (Some(req_id), format!("FxpData {{ len: {} }}", d.data.len())),
+        Ok(resp) => (Some(resp.req_id), format!("{:?}", resp.packet)),
+        Err(e) => (None, format!("undecodable response: {}", e)),
+    }
+}
+
+// Cuts a byte stream, arriving in arbitrary pieces, into whole frames.
+struct Framer {
+    buf: Vec<u8>,
+}
+
+impl Framer {
+    fn new() -> Framer {
+        Framer{buf: Vec::new()}
+    }
+
+    fn push<F>(&mut self, data: &[u8], mut f: F) where F: FnMut(&[u8]) {
+        self.buf.extend_from_slice(data);
+        let mut start = 0;
+        while self.buf.len() - start >= 4 {
+            let b = &self.buf[start..];
+            let len = 4 + BigEndian::read_u32(b) as usize;
+            if b.len() < len {
+                break;
+            }
+            f(&b[..len]);
+            start += len;
+        }
+        self.buf.drain(..start);
+    }
+}
+
+/// Logs every packet passing between a client and a server, decoded and timed.
+///
+/// Wrap both halves of the transport before handing them to `Client::new`:
+///
+/// ```no_run
+/// # use std::{fs, process};
+/// # let mut server = process::Command::new("sftp-server").stdin(process::Stdio::piped())
+/// #     .stdout(process::Stdio::piped()).spawn().unwrap();
+/// let tracer = sftp::Tracer::new(fs::File::create("sftp.trace").unwrap(), sftp::TraceFormat::JsonLines);
+/// let client = sftp::Client::new(tracer.reader(server.stdout.take().unwrap()),
+///                                tracer.writer(server.stdin.take().unwrap())).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct Tracer {
+    log: Arc<Mutex<TraceLog>>,
+}
+
+impl Tracer {
+    pub fn new<T>(out: T, format: TraceFormat) -> Tracer where T: 'static + io::Write + Send {
+        Tracer{log: Arc::new(Mutex::new(TraceLog{out: Box::new(out), format: format, start: Instant::now()}))}
+    }
+
+    /// Wraps the server to client half of the transport.
+    pub fn reader<R>(&self, r: R) -> TraceReader<R> where R: io::Read {
+        TraceReader{inner: r, log: self.log.clone(), framer: Framer::new()}
+    }
+
+    /// Wraps the client to server half of the transport.
+    pub fn writer<W>(&self, w: W) -> TraceWriter<W> where W: io::Write {
+        TraceWriter{inner: w, log: self.log.clone(), framer: Framer::new()}
+    }
+}
+
+pub struct TraceReader<R> {
+    inner: R,
+    log: Arc<Mutex<TraceLog>>,
+    framer: Framer,
+}
+
+impl<R> io::Read for TraceReader<R> where R: io::Read {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        let log = &self.log;
+        self.framer.push(&buf[..n], |frame| log.lock().unwrap().record(Direction::Recv, frame));
+        Ok(n)
+    }
+}
+
+pub struct TraceWriter<W> {
+    inner: W,
+    log: Arc<Mutex<TraceLog>>,
+    framer: Framer,
+}
+
+impl<W> io::Write for TraceWriter<W> where W: io::Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        let log = &self.log;
+        self.framer.push(&buf[..n], |frame| log.lock().unwrap().record(Direction::Send, frame));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.log.lock().unwrap().out.flush());
+        self.inner.flush()
+    }
+}
+
+fn frame_msg_type(frame: &[u8]) -> Option<u8> {
+    frame.get(4).cloned()
+}
+
+// Meaningless for SSH_FXP_INIT and SSH_FXP_VERSION, which have no request id.
+fn frame_req_id(frame: &[u8]) -> Option<u32> {
+    if frame.len() >= 9 {
+        Some(BigEndian::read_u32(&frame[5..9]))
+    } else {
+        None
+    }
+}
+
+struct ReplayState {
+    init_sent: bool,
+    sent: HashSet<u32>,
+    closed: bool,
+}
+
+type SharedReplayState = Arc<(Mutex<ReplayState>, Condvar)>;
+
+/// Plays the server side of a `TraceFormat::JsonLines` recording back to a client, to reproduce
+/// a session without the original server.
+///
+/// Each recorded response is released only once the client has sent the request it answers, so
+/// the client sees the same interleaving as in the recording. The client has to issue the same
+/// requests, in the same order, as the one that was recorded; what it sends is otherwise ignored.
+pub struct Replay {
+    frames: Vec<Vec<u8>>,
+}
+
+impl Replay {
+    /// Reads a recording made with `TraceFormat::JsonLines`. Only the server's packets are kept.
+    pub fn from_json_lines<R>(r: R) -> Result<Replay> where R: BufRead {
+        let mut frames = Vec::new();
+        for (i, line) in r.lines().enumerate() {
+            let line = try!(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = match Json::from_str(&line) {
+                Ok(record) => record,
+                Err(_) => return Err(Error::InvalidTrace(i + 1)),
+            };
+            let dir = record.find("dir").and_then(|d| d.as_string());
+            let raw = record.find("raw").and_then(|r| r.as_string()).and_then(|r| r.from_hex().ok());
+            match (dir, raw) {
+                (Some("recv"), Some(raw)) => frames.push(raw),
+                (Some("send"), Some(_)) => {},
+                _ => return Err(Error::InvalidTrace(i + 1)),
+            }
+        }
+        Ok(Replay{frames: frames})
+    }
+
+    /// Returns the two halves of a transport that plays back the recording, for `Client::new`.
+    pub fn transport(self) -> (ReplayReader, ReplayWriter) {
+        let state = Arc::new((Mutex::new(ReplayState{init_sent: false, sent: HashSet::new(), closed: false}),
+                              Condvar::new()));
+        let reader = ReplayReader{frames: self.frames.into_iter(), current: io::Cursor::new(Vec::new()),
+                                  state: state.clone()};
+        let writer = ReplayWriter{framer: Framer::new(), state: state};
+        (reader, writer)
+    }
+
+    /// Connects a new client to the recording.
+    pub fn client(self) -> Result<Client<ReplayWriter>> {
+        let (r, w) = self.transport();
+        Client::new(r, w)
+    }
+}
+
+pub struct ReplayReader {
+    frames: ::std::vec::IntoIter<Vec<u8>>,
+    current: io::Cursor<Vec<u8>>,
+    state: SharedReplayState,
+}
+
+impl ReplayReader {
+    // Blocks until the client has sent whatever `frame` answers. Returns false if it never will.
+    fn wait_for_request(&self, frame: &[u8]) -> bool {
+        let &(ref lock, ref cvar) = &*self.state;
+        let mut state = lock.lock().unwrap();
+        loop {
+            let ready = match frame_req_id(frame) {
+                _ if frame_msg_type(frame) == Some(packets::SSH_FXP_VERSION) => state.init_sent,
+                Some(req_id) => state.sent.contains(&req_id),
+                None => true,
+            };
+            if ready {
+                return true;
+            }
+            if state.closed {
+                return false;
+            }
+            state = cvar.wait(state).unwrap();
+        }
+    }
+}
+
+impl io::Read for ReplayReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = try!(self.current.read(buf));
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let frame = match self.frames.next() {
+                Some(frame) => frame,
+                None => return Ok(0),
+            };
+            if !self.wait_for_request(&frame) {
+                return Ok(0);
+            }
+            self.current = io::Cursor::new(frame);
+        }
+    }
+}
+
+pub struct ReplayWriter {
+    framer: Framer,
+    state: SharedReplayState,
+}
+
+impl io::Write for ReplayWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let &(ref lock, ref cvar) = &*self.state;
+        self.framer.push(buf, |frame| {
+            let mut state = lock.lock().unwrap();
+            if frame_msg_type(frame) == Some(packets::SSH_FXP_INIT) {
+                state.init_sent = true;
+            } else if let Some(req_id) = frame_req_id(frame) {
+                state.sent.insert(req_id);
+            }
+            cvar.notify_all();
+        });
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ReplayWriter {
+    // The client is gone, so nothing the reader is waiting for will arrive.
+    fn drop(&mut self) {
+        let &(ref lock, ref cvar) = &*self.state;
+        lock.lock().unwrap().closed = true;
+        cvar.notify_all();
+    }
+}
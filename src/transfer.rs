@@ -0,0 +1,508 @@
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use checksum::HashAlgorithm;
+use error::{Error, Result};
+use packets;
+use path::RemotePath;
+use progress::{Progress, Tracker};
+use ratelimit::RateLimiter;
+use {Client, ClientSender, File, Pending, SSH_FXF_READ};
+
+const DEFAULT_SEGMENT_SIZE : u64 = 8 * 1024 * 1024;
+const DEFAULT_HANDLES : usize = 4;
+const DEFAULT_RETRIES : u32 = 3;
+// Number of read and write requests kept in flight when streaming through the client.
+const PIPELINE_DEPTH : usize = 16;
+
+struct Segment {
+    offset: u64,
+    // None means read until the server reports EOF.
+    len: Option<u64>,
+    attempts: u32,
+}
+
+// Per-transfer hooks shared by every worker.
+struct Transfer {
+    tracker: Option<Arc<Tracker>>,
+    limiter: Option<RateLimiter>,
+}
+
+struct DownloadState {
+    queue: VecDeque<Segment>,
+    // Segments not yet downloaded, queued or being fetched.
+    remaining: usize,
+    error: Option<Error>,
+    disconnected: Option<Error>,
+}
+
+// Workers with nothing to do wait on `changed` while others are still fetching, because a segment
+// can come back to the queue when its connection drops.
+struct Shared {
+    state: Mutex<DownloadState>,
+    changed: Condvar,
+}
+
+/// Downloads a single remote file by splitting it into byte ranges that are fetched concurrently
+/// over several handles, optionally spread across several clients connected to the same server.
+pub struct SegmentedDownload<'a, W> where W: 'static + io::Write + Send {
+    client: &'a mut Client<W>,
+    senders: Vec<Arc<ClientSender<W>>>,
+    handles: usize,
+    segment_size: u64,
+    retries: u32,
+    progress: Option<Arc<Progress>>,
+    limiter: Option<RateLimiter>,
+    verify: Option<HashAlgorithm>,
+}
+
+impl<'a, W> SegmentedDownload<'a, W> where W : 'static + io::Write + Send {
+    pub fn new(client: &'a mut Client<W>) -> SegmentedDownload<'a, W> {
+        let sender = client.sender.clone();
+        SegmentedDownload{
+            client: client,
+            senders: vec![sender],
+            handles: DEFAULT_HANDLES,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            retries: DEFAULT_RETRIES,
+            progress: None,
+            limiter: None,
+            verify: None,
+        }
+    }
+
+    /// Adds another connection to the same server. Each connection opens its own handles.
+    pub fn client(&mut self, client: &Client<W>) -> &mut SegmentedDownload<'a, W> {
+        self.senders.push(client.sender.clone());
+        self
+    }
+
+    /// Number of handles opened concurrently on each connection.
+    pub fn handles(&mut self, handles: usize) -> &mut SegmentedDownload<'a, W> {
+        self.handles = cmp::max(handles, 1);
+        self
+    }
+
+    pub fn segment_size(&mut self, segment_size: u64) -> &mut SegmentedDownload<'a, W> {
+        self.segment_size = cmp::max(segment_size, 1);
+        self
+    }
+
+    /// Number of times a failed segment is retried before the download is abandoned.
+    pub fn retries(&mut self, retries: u32) -> &mut SegmentedDownload<'a, W> {
+        self.retries = retries;
+        self
+    }
+
+    pub fn progress(&mut self, progress: Arc<Progress>) -> &mut SegmentedDownload<'a, W> {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Throttles this download, in addition to any limiter set on the clients themselves.
+    pub fn rate_limit(&mut self, limiter: RateLimiter) -> &mut SegmentedDownload<'a, W> {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// After downloading, compares a checksum of the local file against one of the remote file.
+    pub fn verify(&mut self, algorithm: HashAlgorithm) -> &mut SegmentedDownload<'a, W> {
+        self.verify = Some(algorithm);
+        self
+    }
+
+    pub fn download<P, Q>(&mut self, remote: P, local: Q) -> Result<u64> where P: AsRef<RemotePath>, Q: AsRef<Path> {
+        let remote = remote.as_ref();
+        let local = local.as_ref().to_path_buf();
+        let size = try!(self.client.stat(remote)).size;
+
+        let local_file = try!(fs::File::create(&local));
+        let mut queue = VecDeque::new();
+        match size {
+            Some(size) => {
+                try!(local_file.set_len(size));
+                let mut offset = 0;
+                while offset < size {
+                    let len = cmp::min(self.segment_size, size - offset);
+                    queue.push_back(Segment{offset: offset, len: Some(len), attempts: 0});
+                    offset += len;
+                }
+            },
+            None => queue.push_back(Segment{offset: 0, len: None, attempts: 0}),
+        }
+        drop(local_file);
+        let progress = self.progress.clone().or_else(|| self.client.sender.progress.lock().unwrap().clone());
+        let tracker = progress.map(|p| Arc::new(Tracker::new(p, remote.to_string_lossy().into_owned(), size)));
+
+        let shared = Arc::new(Shared{
+            state: Mutex::new(DownloadState{
+                remaining: queue.len(),
+                queue: queue,
+                error: None,
+                disconnected: None,
+            }),
+            changed: Condvar::new(),
+        });
+        let remote_path = remote;
+        let remote = try!(self.client.sender.encode_path(remote));
+        let mut workers = Vec::new();
+        for sender in self.senders.iter() {
+            let handles = match sender.limits.max_open_handles {
+                0 => self.handles,
+                max => cmp::min(self.handles, max as usize),
+            };
+            for _ in 0..handles {
+                let sender = sender.clone();
+                let remote = remote.clone();
+                let local = local.clone();
+                let shared = shared.clone();
+                let transfer = Transfer{tracker: tracker.clone(), limiter: self.limiter.clone()};
+                let retries = self.retries;
+                workers.push(thread::spawn(move || worker(sender, remote, local, shared, transfer, retries)));
+            }
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+        if let Some(tracker) = tracker {
+            tracker.finish();
+        }
+
+        let mut state = shared.state.lock().unwrap();
+        if let Some(e) = state.error.take() {
+            return Err(e);
+        }
+        if state.remaining > 0 {
+            return Err(match state.disconnected.take() {
+                Some(e) => e,
+                None => Error::Io(io::Error::new(io::ErrorKind::Other, "segmented download did not complete")),
+            });
+        }
+        let verify = self.verify.or(*self.client.sender.verify.lock().unwrap());
+        if let Some(algorithm) = verify {
+            let local_sum = try!(algorithm.digest(&mut try!(fs::File::open(&local))));
+            if local_sum != try!(self.client.checksum(remote_path, algorithm, None)) {
+                return Err(Error::ChecksumMismatch);
+            }
+        }
+        Ok(try!(fs::metadata(&local)).len())
+    }
+}
+
+fn worker<W>(sender: Arc<ClientSender<W>>, remote: Vec<u8>, local: PathBuf,
+             shared: Arc<Shared>, transfer: Transfer, retries: u32) where W : 'static + io::Write + Send {
+    let mut local = match fs::OpenOptions::new().write(true).open(&local) {
+        Ok(f) => f,
+        Err(e) => {
+            let mut state = shared.state.lock().unwrap();
+            if state.error.is_none() {
+                state.error = Some(Error::Io(e));
+            }
+            shared.changed.notify_all();
+            return;
+        },
+    };
+    let mut remote_file = None;
+    loop {
+        let mut segment = {
+            let mut state = shared.state.lock().unwrap();
+            loop {
+                if state.error.is_some() || state.remaining == 0 {
+                    return;
+                }
+                match state.queue.pop_front() {
+                    Some(segment) => break segment,
+                    // Everything left is being fetched by other workers, but may yet be handed
+                    // back if their connection drops.
+                    None => state = shared.changed.wait(state).unwrap(),
+                }
+            }
+        };
+        let mut fetched = 0;
+        let result = fetch_segment(&sender, &remote, &mut remote_file, &mut local, &segment, &transfer, &mut fetched);
+        let mut state = shared.state.lock().unwrap();
+        shared.changed.notify_all();
+        match result {
+            Ok(()) => {
+                state.remaining -= 1;
+            },
+            Err(e) => {
+                if let Some(ref tracker) = transfer.tracker {
+                    tracker.sub(fetched);
+                }
+                // Start over with a fresh handle in case the old one is what went bad.
+                remote_file = None;
+                segment.attempts += 1;
+                if segment.attempts > retries {
+                    if state.error.is_none() {
+                        state.error = Some(e);
+                    }
+                    return;
+                }
+                state.queue.push_back(segment);
+                if let Error::ReceiverDisconnected(_) = e {
+                    // This connection is gone; leave the segment for the workers that are still
+                    // running. If there are none, the download fails with this error.
+                    state.disconnected = Some(e);
+                    return;
+                }
+            },
+        }
+    }
+}
+
+fn fetch_segment<W>(sender: &Arc<ClientSender<W>>, remote: &Vec<u8>, remote_file: &mut Option<File<W>>,
+                    local: &mut fs::File, segment: &Segment, transfer: &Transfer,
+                    fetched: &mut u64) -> Result<()> where W : 'static + io::Write + Send {
+    if remote_file.is_none() {
+        *remote_file = Some(try!(File::open(sender.clone(), remote.clone(), SSH_FXF_READ, packets::FileAttr::new())));
+    }
+    let file = remote_file.as_ref().unwrap();
+    try!(local.seek(io::SeekFrom::Start(segment.offset)));
+    let mut buf = vec![0; sender.max_read_len()];
+    let mut offset = segment.offset;
+    loop {
+        let want = match segment.len {
+            Some(len) => cmp::min(segment.offset + len - offset, buf.len() as u64) as usize,
+            None => buf.len(),
+        };
+        if want == 0 {
+            return Ok(());
+        }
+        if let Some(ref limiter) = transfer.limiter {
+            limiter.acquire(want as u64);
+        }
+        let n = try!(file.read_at(&mut buf[..want], offset));
+        if n == 0 {
+            return match segment.len {
+                Some(_) => Err(Error::UnexpectedEOF),
+                None => Ok(()),
+            };
+        }
+        try!(local.write_all(&buf[..n]));
+        offset += n as u64;
+        *fetched += n as u64;
+        if let Some(ref tracker) = transfer.tracker {
+            tracker.add(n as u64);
+        }
+    }
+}
+
+// Copies between two open handles, using the copy-data extension when the server has it. A `len`
+// of zero means until the end of `src`. Returns how much was copied, which the server does not
+// say when it does the copy, so then it is worked out from `src_size`.
+pub fn copy_data<W>(src: &File<W>, src_offset: u64, len: u64, src_size: Option<u64>, dst: &File<W>, dst_offset: u64,
+                    tracker: Option<&Tracker>) -> Result<u64>
+    where W : 'static + io::Write + Send {
+    if src.client.has_extension(packets::COPY_DATA) {
+        let p = packets::FxpCopyData{
+            read_handle: src.handle.clone(),
+            read_offset: src_offset,
+            read_len: len,
+            write_handle: dst.handle.clone(),
+            write_offset: dst_offset,
+        };
+        match try!(src.client.send_receive(&p)) {
+            packets::SftpResponsePacket::Status(packets::FxpStatus{code:
+                packets::FxpStatusCode::OpUnsupported, msg: _}) => {},
+            resp => {
+                try!(Client::<W>::expect_status_response(resp));
+                let copied = copy_len(src_size, src_offset, len).unwrap_or(len);
+                if let Some(tracker) = tracker {
+                    tracker.add(copied);
+                }
+                return Ok(copied);
+            },
+        }
+    }
+    pipelined_copy(src, src_offset, len, dst, dst_offset, tracker)
+}
+
+// How much of a file of `size` bytes a copy of `len` bytes from `offset` covers, or None if that
+// depends on a size that is not known.
+pub fn copy_len(size: Option<u64>, offset: u64, len: u64) -> Option<u64> {
+    match size.map(|size| size.saturating_sub(offset)) {
+        Some(rest) if len == 0 || rest < len => Some(rest),
+        _ if len > 0 => Some(len),
+        _ => None,
+    }
+}
+
+fn send_read<W>(src: &File<W>, offset: u64, len: u32) -> Result<Pending> where W : 'static + io::Write + Send {
+    src.client.send(&packets::FxpRead{handle: src.handle.clone(), offset: offset, len: len})
+}
+
+// Waits for a write of `n` bytes to be acknowledged, and only then counts it as transferred.
+fn wait_write(pending: Pending, n: u64, tracker: Option<&Tracker>) -> Result<()> {
+    match try!(pending.recv()) {
+        packets::SftpResponsePacket::Status(packets::FxpStatus{code: packets::FxpStatusCode::Ok, msg: _}) => {
+            if let Some(tracker) = tracker {
+                tracker.add(n);
+            }
+            Ok(())
+        },
+        packets::SftpResponsePacket::Status(status) => Err(Error::FromServer(Box::new(status))),
+        x => Err(Error::UnexpectedResponse(Box::new(x))),
+    }
+}
+
+// Streams data through the client, keeping several reads and writes outstanding at once. Writes
+// carry explicit offsets, so they can be issued as soon as each read completes.
+fn pipelined_copy<W>(src: &File<W>, src_offset: u64, len: u64, dst: &File<W>, dst_offset: u64,
+                     tracker: Option<&Tracker>) -> Result<u64>
+    where W : 'static + io::Write + Send {
+    let end = if len == 0 { None } else { Some(src_offset + len) };
+    let chunk = cmp::min(src.client.max_read_len(), dst.client.max_write_len()) as u64;
+    let mut next = src_offset;
+    let mut eof = false;
+    let mut copied = 0;
+    let mut reads : VecDeque<(u64, u32, Pending)> = VecDeque::new();
+    let mut writes : VecDeque<(u64, Pending)> = VecDeque::new();
+    loop {
+        while !eof && reads.len() < PIPELINE_DEPTH && end.map_or(true, |end| next < end) {
+            let n = match end {
+                Some(end) => cmp::min(end - next, chunk) as u32,
+                None => chunk as u32,
+            };
+            reads.push_back((next, n, try!(send_read(src, next, n))));
+            next += n as u64;
+        }
+        let (offset, n, pending) = match reads.pop_front() {
+            Some(read) => read,
+            None => break,
+        };
+        match try!(pending.recv()) {
+            packets::SftpResponsePacket::Data(data) => {
+                let got = data.data.len() as u32;
+                if got == 0 {
+                    eof = true;
+                    continue;
+                }
+                if got > n {
+                    return Err(Error::UnexpectedData);
+                }
+                if got < n {
+                    // Short read; ask again for the rest of the range.
+                    reads.push_back((offset + got as u64, n - got, try!(send_read(src, offset + got as u64, n - got))));
+                }
+                let p = packets::FxpWrite{
+                    handle: Cow::Borrowed(&dst.handle),
+                    offset: dst_offset + (offset - src_offset),
+                    data: Cow::Borrowed(&data.data),
+                };
+                writes.push_back((got as u64, try!(dst.client.send(&p))));
+                copied += got as u64;
+            },
+            packets::SftpResponsePacket::Status(packets::FxpStatus{code: packets::FxpStatusCode::EOF, msg: _}) => {
+                eof = true;
+            },
+            packets::SftpResponsePacket::Status(status) => return Err(Error::FromServer(Box::new(status))),
+            x => return Err(Error::UnexpectedResponse(Box::new(x))),
+        }
+        while writes.len() > PIPELINE_DEPTH {
+            let (n, pending) = writes.pop_front().unwrap();
+            try!(wait_write(pending, n, tracker));
+        }
+    }
+    for (n, pending) in writes {
+        try!(wait_write(pending, n, tracker));
+    }
+    Ok(copied)
+}
+
+// Reads from `offset` to the end of the file with several reads outstanding at once. `size_hint`
+// is only used to preallocate, and only as much as the reads in flight can fill, since it comes
+// from the server; reading continues until the server reports EOF.
+pub fn read_to_end<W>(file: &File<W>, offset: u64, size_hint: Option<u64>, tracker: Option<&Tracker>) -> Result<Vec<u8>>
+    where W : 'static + io::Write + Send {
+    let chunk = file.client.max_read_len() as u32;
+    let preallocate = cmp::min(size_hint.map_or(0, |size| size.saturating_sub(offset)), PIPELINE_DEPTH as u64 * chunk as u64);
+    let mut buf = Vec::with_capacity(preallocate as usize);
+    // Bytes reported to the tracker, some of which may turn out to lie past the end of the file.
+    let mut counted = 0;
+    let mut next = offset;
+    let mut eof_at : Option<u64> = None;
+    let mut reads : VecDeque<(u64, u32, Pending)> = VecDeque::new();
+    loop {
+        while eof_at.is_none() && reads.len() < PIPELINE_DEPTH {
+            reads.push_back((next, chunk, try!(send_read(file, next, chunk))));
+            next += chunk as u64;
+        }
+        let (at, n, pending) = match reads.pop_front() {
+            Some(read) => read,
+            None => break,
+        };
+        match try!(pending.recv()) {
+            packets::SftpResponsePacket::Data(data) => {
+                let got = data.data.len() as u32;
+                if got == 0 {
+                    eof_at = Some(eof_at.map_or(at, |eof| cmp::min(eof, at)));
+                    continue;
+                }
+                if got > n {
+                    return Err(Error::UnexpectedData);
+                }
+                if got < n {
+                    // Short read; ask for the rest before looking at anything after it.
+                    let rest = at + got as u64;
+                    reads.push_front((rest, n - got, try!(send_read(file, rest, n - got))));
+                }
+                let start = (at - offset) as usize;
+                if buf.len() < start + got as usize {
+                    buf.resize(start + got as usize, 0);
+                }
+                buf[start..start + got as usize].clone_from_slice(&data.data);
+                if let Some(tracker) = tracker {
+                    tracker.add(got as u64);
+                }
+                counted += got as u64;
+            },
+            packets::SftpResponsePacket::Status(packets::FxpStatus{code: packets::FxpStatusCode::EOF, msg: _}) => {
+                eof_at = Some(eof_at.map_or(at, |eof| cmp::min(eof, at)));
+            },
+            packets::SftpResponsePacket::Status(status) => return Err(Error::FromServer(Box::new(status))),
+            x => return Err(Error::UnexpectedResponse(Box::new(x))),
+        }
+    }
+    if let Some(eof) = eof_at {
+        buf.truncate((eof - offset) as usize);
+    }
+    // Everything before the end was read, and nothing twice, so whatever was counted beyond the
+    // buffer is data from after the end that was dropped.
+    if let Some(tracker) = tracker {
+        if counted > buf.len() as u64 {
+            tracker.sub(counted - buf.len() as u64);
+        }
+    }
+    Ok(buf)
+}
+
+// Writes all of `data` at `offset` with several writes outstanding at once.
+pub fn write_all_at<W>(file: &File<W>, offset: u64, data: &[u8], tracker: Option<&Tracker>) -> Result<()>
+    where W : 'static + io::Write + Send {
+    let mut writes : VecDeque<(u64, Pending)> = VecDeque::new();
+    let chunk_len = file.client.max_write_len();
+    for (i, chunk) in data.chunks(chunk_len).enumerate() {
+        let p = packets::FxpWrite{
+            handle: Cow::Borrowed(&file.handle),
+            offset: offset + (i * chunk_len) as u64,
+            data: Cow::Borrowed(chunk),
+        };
+        writes.push_back((chunk.len() as u64, try!(file.client.send(&p))));
+        while writes.len() > PIPELINE_DEPTH {
+            let (n, pending) = writes.pop_front().unwrap();
+            try!(wait_write(pending, n, tracker));
+        }
+    }
+    for (n, pending) in writes {
+        try!(wait_write(pending, n, tracker));
+    }
+    Ok(())
+}
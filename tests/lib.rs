@@ -12,7 +12,10 @@ use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
 use std::fs::File;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 struct TempFile {
     file: tempfile::NamedTempFile,
@@ -54,32 +57,6 @@ impl Write for TempFile {
     fn flush(&mut self) -> io::Result<()> { self.file.flush() }
 }
 
-struct DebugWriter<W> {
-    inner: W,
-}           
-            
-impl<W : io::Write> io::Write for DebugWriter<W> {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        writeln!(&mut io::stderr(), "Writing to server: {:?}", buf).unwrap();
-        self.inner.write(buf)
-    }
-    fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
-    }
-}
-
-struct DebugReader<R> {
-    inner: R,
-}
-
-impl<R : io::Read> io::Read for DebugReader<R> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let res = self.inner.read(buf);
-        writeln!(&mut io::stderr(), "Reading from server: {:?}", buf).unwrap();
-        res
-    }
-}
-
 struct TestSftpServer {
     server: process::Child,
 }
@@ -110,9 +87,10 @@ impl TestSftpServer {
     }
 
     #[allow(dead_code)]
-    fn debug_client(&mut self) -> sftp::Client<DebugWriter<process::ChildStdin>> {
-        let r = DebugReader{inner: self.server.stdout.take().unwrap()};
-        let w = DebugWriter{inner: self.server.stdin.take().unwrap()};
+    fn debug_client(&mut self) -> sftp::Client<sftp::TraceWriter<process::ChildStdin>> {
+        let tracer = sftp::Tracer::new(io::stderr(), sftp::TraceFormat::Text);
+        let r = tracer.reader(self.server.stdout.take().unwrap());
+        let w = tracer.writer(self.server.stdin.take().unwrap());
         sftp::Client::new(r, w).unwrap()
     }
 }
@@ -285,7 +263,7 @@ fn can_realpath() {
     let mut server = TestSftpServer::new();
     let mut client = server.client();
     let name = client.realpath(tempfile.path()).unwrap();
-    assert_eq!(tempfile.path().to_string().into_bytes(), name.filename);
+    assert_eq!(tempfile.path().as_bytes(), name.as_bytes());
 }
 
 #[test]
@@ -310,7 +288,7 @@ fn can_readlink() {
     let mut server = TestSftpServer::new();
     let mut client = server.client();
     let dst = client.readlink(linkpath.as_str()).unwrap();
-    assert_eq!(LINK_TARGET.as_bytes(), dst.filename.as_slice());
+    assert_eq!(LINK_TARGET.as_bytes(), dst.as_bytes());
     let _ = std::fs::remove_file(linkpath);
 }
 
@@ -329,7 +307,7 @@ fn can_readdir() {
     let mut server = TestSftpServer::new();
     let mut client = server.client();
     for file in client.readdir(tmp_dir.path().to_str().unwrap().to_string()).unwrap().map(|x| x.unwrap()) {
-        let fname = String::from_utf8(file.filename).unwrap();
+        let fname = file.file_name().to_str().unwrap().to_string();
         if fname == "." || fname == ".." {
             continue;
         }
@@ -340,3 +318,862 @@ fn can_readdir() {
     }
     assert_eq!(0, files.len());
 }
+
+#[test]
+fn can_segmented_download() {
+    let mut tempfile = TempFile::new();
+    let contents : Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+    tempfile.write_all(&contents[..]).unwrap();
+    let local = TempFile::new();
+    let mut server1 = TestSftpServer::new();
+    let mut server2 = TestSftpServer::new();
+    let mut client1 = server1.client();
+    let client2 = server2.client();
+    let n = client1.segmented_download()
+        .client(&client2)
+        .handles(2)
+        .segment_size(7000)
+        .download(tempfile.path(), local.path())
+        .unwrap();
+    assert_eq!(contents.len() as u64, n);
+    let mut local_contents = Vec::new();
+    File::open(local.path()).unwrap().read_to_end(&mut local_contents).unwrap();
+    assert_eq!(contents, local_contents);
+}
+
+#[test]
+fn reports_progress() {
+    let mut tempfile = TempFile::new();
+    let contents : Vec<u8> = (0..50000).map(|i| (i % 251) as u8).collect();
+    tempfile.write_all(&contents[..]).unwrap();
+    let local = TempFile::new();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let last = Arc::new(Mutex::new(None));
+    let last_clone = last.clone();
+    let progress = Arc::new(move |status: &sftp::TransferStatus| {
+        *last_clone.lock().unwrap() = Some(status.clone());
+    });
+    client.segmented_download()
+        .segment_size(7000)
+        .progress(progress.clone())
+        .download(tempfile.path(), local.path())
+        .unwrap();
+    let status = last.lock().unwrap().take().unwrap();
+    assert_eq!(contents.len() as u64, status.transferred);
+    assert_eq!(Some(contents.len() as u64), status.total);
+
+    let file = client.open_options().read(true).open(tempfile.path()).unwrap();
+    let mut reader = sftp::WithProgress::file(file, tempfile.path(), progress).unwrap();
+    let mut copied = Vec::new();
+    io::copy(&mut reader, &mut copied).unwrap();
+    let status = last.lock().unwrap().take().unwrap();
+    assert_eq!(contents.len() as u64, status.transferred);
+    assert_eq!(contents, copied);
+}
+
+// Records every progress event as (event, path, transferred).
+struct Events(Mutex<Vec<(&'static str, String, u64)>>);
+
+impl sftp::Progress for Events {
+    fn started(&self, path: &str, _total: Option<u64>) {
+        self.0.lock().unwrap().push(("started", path.to_string(), 0));
+    }
+
+    fn update(&self, _status: &sftp::TransferStatus) {}
+
+    fn finished(&self, status: &sftp::TransferStatus) {
+        self.0.lock().unwrap().push(("finished", status.path.clone(), status.transferred));
+    }
+}
+
+#[test]
+fn reports_progress_of_client_helpers() {
+    let tempdir = tempdir::TempDir::new("sftp_progress").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let events = Arc::new(Events(Mutex::new(Vec::new())));
+    client.set_progress(Some(events.clone()));
+    let contents : Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+    let file = tempdir.path().join("file");
+    let file = file.to_str().unwrap();
+    let copy = tempdir.path().join("copy");
+    let copy = copy.to_str().unwrap();
+
+    client.write(file, &contents).unwrap();
+    client.append(file, &contents).unwrap();
+    client.read(file).unwrap();
+    client.copy(file, copy).unwrap();
+    client.write_atomic(copy, &contents).unwrap();
+    let len = contents.len() as u64;
+    let finished : Vec<(String, u64)> = events.0.lock().unwrap().drain(..)
+        .filter(|e| e.0 == "finished").map(|e| (e.1, e.2)).collect();
+    assert_eq!(5, finished.len());
+    assert_eq!((file.to_string(), len), finished[0]);
+    assert_eq!((file.to_string(), len), finished[1]);
+    assert_eq!((file.to_string(), 2 * len), finished[2]);
+    assert_eq!((file.to_string(), 2 * len), finished[3]);
+    assert_eq!(copy.to_string(), finished[4].0);
+    assert_eq!(len, finished[4].1);
+
+    let nested = tempdir.path().join("a/b");
+    client.create_dir_all(nested.to_str().unwrap(), 0o755).unwrap();
+    client.write(nested.join("f").to_str().unwrap(), b"x").unwrap();
+    events.0.lock().unwrap().clear();
+    client.remove_dir_all(tempdir.path().join("a").to_str().unwrap()).unwrap();
+    let events = events.0.lock().unwrap();
+    let started = events.iter().filter(|e| e.0 == "started").count();
+    let finished = events.iter().filter(|e| e.0 == "finished").count();
+    assert_eq!(3, started);
+    assert_eq!(3, finished);
+    assert!(events.iter().any(|e| e.1.ends_with("a/b/f")));
+}
+
+// Advances only when slept on, so rate limits can be checked without depending on the machine's
+// speed.
+struct FakeClock {
+    start: Instant,
+    slept: Mutex<Duration>,
+}
+
+impl FakeClock {
+    fn new() -> FakeClock {
+        FakeClock{start: Instant::now(), slept: Mutex::new(Duration::from_millis(0))}
+    }
+
+    fn slept(&self) -> Duration {
+        *self.slept.lock().unwrap()
+    }
+}
+
+impl sftp::Clock for FakeClock {
+    fn now(&self) -> Instant {
+        self.start + self.slept()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        *self.slept.lock().unwrap() += duration;
+    }
+}
+
+#[test]
+fn rate_limits_writes() {
+    let tempfile = TempFile::new();
+    let contents : Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let clock = Arc::new(FakeClock::new());
+    let limiter = sftp::RateLimiter::with_clock(50000, clock.clone());
+    client.set_rate_limiter(Some(limiter.clone()));
+    let mut remote_file = client.open_options().write(true).open(tempfile.path()).unwrap();
+    remote_file.write_all(&contents[..]).unwrap();
+    // The bucket starts full, so the first 50000 bytes go out at once and the rest take a second.
+    let slept = clock.slept();
+    assert!(slept >= Duration::from_millis(950), "{:?}", slept);
+    assert!(slept <= Duration::from_millis(1100), "{:?}", slept);
+    limiter.set_rate(0);
+    remote_file.write_all(&contents[..]).unwrap();
+    assert_eq!(slept, clock.slept());
+}
+
+#[test]
+fn can_copy() {
+    let mut tempfile = TempFile::new();
+    let contents : Vec<u8> = (0..200000).map(|i| (i % 251) as u8).collect();
+    tempfile.write_all(&contents[..]).unwrap();
+    let dst = TempFile::new();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    client.copy(tempfile.path(), dst.path()).unwrap();
+    let mut copied = Vec::new();
+    File::open(dst.path()).unwrap().read_to_end(&mut copied).unwrap();
+    assert_eq!(contents, copied);
+
+    client.copy_range(tempfile.path(), 1000, 5000, dst.path(), 10).unwrap();
+    let mut copied = Vec::new();
+    File::open(dst.path()).unwrap().read_to_end(&mut copied).unwrap();
+    assert_eq!(&contents[1000..6000], &copied[10..5010]);
+    assert_eq!(&contents[..10], &copied[..10]);
+}
+
+#[test]
+fn can_checksum() {
+    let mut tempfile = TempFile::new();
+    let contents : Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+    tempfile.write_all(&contents[..]).unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let algorithm = sftp::HashAlgorithm::Sha256;
+    let expected = algorithm.digest(&mut &contents[..]).unwrap();
+    assert_eq!(expected, client.checksum(tempfile.path(), algorithm, None).unwrap());
+    let expected = algorithm.digest(&mut &contents[100..60000]).unwrap();
+    assert_eq!(expected, client.checksum(tempfile.path(), algorithm, Some(100..60000)).unwrap());
+
+    let local = TempFile::new();
+    client.segmented_download()
+        .segment_size(30000)
+        .verify(sftp::HashAlgorithm::Md5)
+        .download(tempfile.path(), local.path())
+        .unwrap();
+
+    let dst = TempFile::new();
+    client.set_verify(Some(sftp::HashAlgorithm::Sha1));
+    client.write(dst.path(), &contents).unwrap();
+    client.append(dst.path(), &contents).unwrap();
+    assert_eq!(2 * contents.len(), client.read(dst.path()).unwrap().len());
+    client.copy(tempfile.path(), dst.path()).unwrap();
+    assert_eq!(5000, client.copy_range(tempfile.path(), 1000, 5000, dst.path(), 10).unwrap());
+    client.write_atomic(dst.path(), &contents).unwrap();
+}
+
+#[test]
+fn can_get_metadata() {
+    const CONTENTS : &'static str = "tempfile contents";
+    let mut tempfile = TempFile::new();
+    tempfile.write_all(CONTENTS.as_bytes()).unwrap();
+    let link = tempfile.symlink();
+    let tmp_dir = tempdir::TempDir::new("sftp_metadata").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+
+    let local = std::fs::metadata(tempfile.path()).unwrap();
+    let metadata = client.metadata(tempfile.path()).unwrap();
+    assert!(metadata.is_file());
+    assert!(!metadata.is_dir());
+    assert_eq!(CONTENTS.len() as u64, metadata.len());
+    assert_eq!(local.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs(),
+               metadata.modified().unwrap().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs());
+    assert_eq!(local.permissions().mode() & 0o7777, metadata.permissions().mode() & 0o7777);
+
+    assert!(client.symlink_metadata(link.as_str()).unwrap().is_symlink());
+    assert!(client.metadata(link.as_str()).unwrap().is_file());
+    assert!(client.metadata(tmp_dir.path().to_str().unwrap()).unwrap().is_dir());
+
+    let mut perms = metadata.permissions();
+    perms.set_readonly(true);
+    client.set_permissions(tempfile.path(), perms).unwrap();
+    assert!(std::fs::metadata(tempfile.path()).unwrap().permissions().readonly());
+    let from_local = sftp::Metadata::from(std::fs::metadata(tempfile.path()).unwrap());
+    assert!(from_local.permissions().readonly());
+}
+
+#[test]
+fn can_use_non_utf8_paths() {
+    use std::os::unix::ffi::OsStrExt;
+    let tmp_dir = tempdir::TempDir::new("sftp_non_utf8").unwrap();
+    let name : &[u8] = b"caf\xe9.txt";
+    let local_path = tmp_dir.path().join(std::ffi::OsStr::from_bytes(name));
+    File::create(&local_path).unwrap().write_all(b"contents").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let dir = sftp::RemotePathBuf::from(tmp_dir.path().to_str().unwrap());
+    let entry = client.readdir(&dir).unwrap().map(|x| x.unwrap()).find(|x| x.file_name().as_bytes() == name).unwrap();
+    let path = entry.path();
+    assert_eq!(Some(&b"txt"[..]), path.extension());
+    assert_eq!(&*dir, path.parent().unwrap());
+    let mut remote_file = client.open_options().read(true).open(&path).unwrap();
+    let mut contents = String::new();
+    remote_file.read_to_string(&mut contents).unwrap();
+    assert_eq!("contents", contents);
+}
+
+#[test]
+fn remote_path_manipulation() {
+    use sftp::RemotePath;
+    let path = RemotePath::new("/a//b/./c.tar.gz");
+    assert_eq!(Some(&b"c.tar.gz"[..]), path.file_name());
+    assert_eq!(Some(&b"gz"[..]), path.extension());
+    assert_eq!(RemotePath::new("/a//b/."), path.parent().unwrap());
+    assert_eq!(vec![sftp::Component::RootDir, sftp::Component::Normal(b"a"), sftp::Component::Normal(b"b"),
+                    sftp::Component::Normal(b"c.tar.gz")],
+               path.components().collect::<Vec<_>>());
+    assert_eq!(RemotePath::new("/a/c"), &*RemotePath::new("/a/b/../c/.").normalize());
+    assert_eq!(RemotePath::new("../x"), &*RemotePath::new("a/../../x").normalize());
+    assert_eq!(RemotePath::new("/x"), &*RemotePath::new("/a").join("/x"));
+    assert_eq!(RemotePath::new("a/b"), &*RemotePath::new("a").join("b"));
+    assert_eq!(RemotePath::new("/"), RemotePath::new("/a").parent().unwrap());
+    assert_eq!(None, RemotePath::new("/").parent());
+}
+
+#[test]
+fn can_use_legacy_filename_encoding() {
+    use std::os::unix::ffi::OsStrExt;
+    let tmp_dir = tempdir::TempDir::new("sftp_latin1").unwrap();
+    let local_path = tmp_dir.path().join(std::ffi::OsStr::from_bytes(b"caf\xe9.txt"));
+    File::create(&local_path).unwrap().write_all(b"contents").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let dir = tmp_dir.path().to_str().unwrap().to_string();
+    assert!(client.readdir(dir.as_str()).unwrap().map(|x| x.unwrap()).any(|x| x.file_name().as_bytes() == b"caf\xe9.txt"));
+    client.set_filename_encoding(sftp::FilenameEncoding::from_label("latin1").unwrap());
+    assert!(client.readdir(dir.as_str()).unwrap().map(|x| x.unwrap()).any(|x| x.file_name().as_bytes() == "caf\u{e9}.txt".as_bytes()));
+    let path = format!("{}/caf\u{e9}.txt", dir);
+    assert_eq!(8, client.stat(path.as_str()).unwrap().size.unwrap());
+}
+
+#[test]
+fn readdir_entries() {
+    let tmp_dir = tempdir::TempDir::new("sftp_direntry").unwrap();
+    File::create(tmp_dir.path().join("file")).unwrap().write_all(b"12345").unwrap();
+    std::fs::create_dir(tmp_dir.path().join("dir")).unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let dir = tmp_dir.path().to_str().unwrap();
+    let mut entries : Vec<sftp::DirEntry> = client.readdir(dir).unwrap().skip_dots(true).map(|x| x.unwrap()).collect();
+    entries.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    assert_eq!(2, entries.len());
+    assert_eq!(b"dir", entries[0].file_name().as_bytes());
+    assert!(entries[0].file_type().is_dir());
+    assert_eq!(format!("{}/file", dir).as_bytes(), entries[1].path().as_bytes());
+    assert!(entries[1].file_type().is_file());
+    assert_eq!(5, entries[1].metadata().len());
+    let longname = entries[1].parse_longname().unwrap();
+    assert_eq!(Some(1), longname.link_count);
+    assert_eq!(Some(5), longname.size);
+    assert!(longname.owner.is_some());
+}
+
+#[test]
+fn parse_longname() {
+    let parsed = sftp::LongName::parse("-rw-r--r--    1 alice    staff        1234 Jan  1 12:00 notes.txt").unwrap();
+    assert_eq!("-rw-r--r--", parsed.permissions);
+    assert_eq!(Some(1), parsed.link_count);
+    assert_eq!(Some("alice".to_string()), parsed.owner);
+    assert_eq!(Some("staff".to_string()), parsed.group);
+    assert_eq!(Some(1234), parsed.size);
+    let parsed = sftp::LongName::parse("drwxr-xr-x   2 bob   4096 Mar 10  2015 dir").unwrap();
+    assert_eq!(Some("bob".to_string()), parsed.owner);
+    assert_eq!(None, parsed.group);
+    assert_eq!(Some(4096), parsed.size);
+    assert_eq!(None, sftp::LongName::parse("notes.txt"));
+}
+
+#[test]
+fn can_list_dir() {
+    let tmp_dir = tempdir::TempDir::new("sftp_list_dir").unwrap();
+    for (name, len) in vec![("b", 30), ("a", 10), ("c", 20)] {
+        File::create(tmp_dir.path().join(name)).unwrap().write_all(&vec![0; len][..]).unwrap();
+    }
+    std::fs::create_dir(tmp_dir.path().join("z")).unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let dir = tmp_dir.path().to_str().unwrap();
+    let names = |entries: Vec<sftp::DirEntry>| -> Vec<String> {
+        entries.iter().map(|e| e.file_name().to_str().unwrap().to_string()).collect()
+    };
+    assert_eq!(vec!["a", "b", "c", "z"], names(client.list_dir(dir, &sftp::ListOptions::new()).unwrap()));
+    assert_eq!(vec!["z", "b", "c", "a"],
+               names(client.list_dir(dir, sftp::ListOptions::new().sort_by(sftp::SortBy::Size).reverse(true)).unwrap()));
+    assert_eq!(vec!["z", "a", "b", "c"],
+               names(client.list_dir(dir, sftp::ListOptions::new().dirs_first(true)).unwrap()));
+}
+
+#[test]
+fn can_create_and_remove_dir_all() {
+    let tmp_dir = tempdir::TempDir::new("sftp_dir_all").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let root = tmp_dir.path().join("a");
+    let nested = root.join("b/c");
+    client.create_dir_all(nested.to_str().unwrap(), 0o750).unwrap();
+    assert!(nested.is_dir());
+    assert_eq!(0o750, std::fs::metadata(&nested).unwrap().permissions().mode() & 0o777);
+    // Existing directories are fine.
+    client.create_dir_all(nested.to_str().unwrap(), 0o750).unwrap();
+
+    let outside = tmp_dir.path().join("outside");
+    std::fs::create_dir(&outside).unwrap();
+    File::create(outside.join("keep")).unwrap();
+    File::create(root.join("b/file")).unwrap();
+    File::create(nested.join("file")).unwrap();
+    std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+
+    client.remove_dir_all(root.to_str().unwrap()).unwrap();
+    assert!(!root.exists());
+    assert!(outside.join("keep").exists());
+
+    match client.remove_dir_all(root.to_str().unwrap()) {
+        Err(sftp::Error::AtPath(ref path, _)) => assert_eq!(root.to_str().unwrap().as_bytes(), path.as_bytes()),
+        x => panic!("unexpected result: {:?}", x),
+    }
+}
+
+#[test]
+fn can_create_with_mode() {
+    let tmp_dir = tempdir::TempDir::new("sftp_create_mode").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let mode = |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let file = tmp_dir.path().join("file");
+    client.open_options().write(true).create(true).mode(0o600).open(file.to_str().unwrap()).unwrap();
+    assert_eq!(0o600, mode(&file));
+
+    let dir = tmp_dir.path().join("dir");
+    let mut attrs = sftp::FileAttr::new();
+    attrs.perms = Some(0o700);
+    client.mkdir_with(dir.to_str().unwrap(), attrs).unwrap();
+    assert_eq!(0o700, mode(&dir));
+
+    let nested = tmp_dir.path().join("x/y");
+    client.dir_builder().recursive(true).mode(0o750).create(nested.to_str().unwrap()).unwrap();
+    assert_eq!(0o750, mode(&nested));
+    assert_eq!(0o750, mode(&tmp_dir.path().join("x")));
+}
+
+#[test]
+fn can_read_and_write_whole_files() {
+    let tmp_dir = tempdir::TempDir::new("sftp_whole_file").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let path = tmp_dir.path().join("file");
+    let path = path.to_str().unwrap();
+    let data : Vec<u8> = (0..1000000).map(|i| (i % 251) as u8).collect();
+
+    client.write(path, &data).unwrap();
+    assert_eq!(data, client.read(path).unwrap());
+
+    client.write(path, "hello").unwrap();
+    client.append(path, " world").unwrap();
+    assert_eq!("hello world", client.read_to_string(path).unwrap());
+
+    client.append(tmp_dir.path().join("new").to_str().unwrap(), "x").unwrap();
+    assert_eq!(b"x".to_vec(), client.read(tmp_dir.path().join("new").to_str().unwrap()).unwrap());
+}
+
+#[test]
+fn can_write_atomic() {
+    let tmp_dir = tempdir::TempDir::new("sftp_write_atomic").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let path = tmp_dir.path().join("file");
+    File::create(&path).unwrap().write_all(b"old").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+    client.write_atomic(path.to_str().unwrap(), "new").unwrap();
+    assert_eq!(b"new".to_vec(), client.read(path.to_str().unwrap()).unwrap());
+    assert_eq!(0o640, std::fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+
+    let written = client.atomic_write().fsync(true).mode(0o600)
+        .write_from(path.to_str().unwrap(), &mut &b"from a reader"[..]).unwrap();
+    assert_eq!(13, written);
+    assert_eq!("from a reader", client.read_to_string(path.to_str().unwrap()).unwrap());
+    assert_eq!(0o600, std::fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+
+    // A failed write leaves the target alone and no temporary file behind.
+    let missing = tmp_dir.path().join("missing/file");
+    assert!(client.write_atomic(missing.to_str().unwrap(), "x").is_err());
+    assert_eq!(1, std::fs::read_dir(tmp_dir.path()).unwrap().count());
+}
+
+#[test]
+fn can_read_and_write_at_offsets_from_threads() {
+    let tmp_dir = tempdir::TempDir::new("sftp_positional").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let path = tmp_dir.path().join("file");
+    let file = Arc::new(client.open_options().read(true).write(true).create(true)
+                        .open(path.to_str().unwrap()).unwrap());
+    let threads : Vec<_> = (0..8u8).map(|i| {
+        let file = file.clone();
+        std::thread::spawn(move || {
+            file.write_all_at(&[i; 1000], i as u64 * 1000).unwrap();
+            let mut buf = [0; 1000];
+            assert_eq!(1000, file.read_at(&mut buf, i as u64 * 1000).unwrap());
+            assert!(buf.iter().all(|&b| b == i));
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let mut buf = [0; 10];
+    assert_eq!(0, file.read_at(&mut buf, 8000).unwrap());
+    assert_eq!(8000, std::fs::metadata(&path).unwrap().len());
+}
+
+#[test]
+fn buffered_file() {
+    use std::io::{BufRead, Seek, SeekFrom};
+    let tmp_dir = tempdir::TempDir::new("sftp_buffered").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let path = tmp_dir.path().join("file");
+    let file = client.open_options().read(true).write(true).create(true).open(path.to_str().unwrap()).unwrap();
+    let mut file = sftp::BufFile::with_capacity(100, file);
+    for i in 0..1000 {
+        writeln!(file, "line {}", i).unwrap();
+    }
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let lines : Vec<String> = (&mut file).lines().map(|l| l.unwrap()).collect();
+    assert_eq!(1000, lines.len());
+    assert_eq!("line 999", lines[999]);
+
+    file.seek(SeekFrom::Start(5)).unwrap();
+    file.write_all(b"X").unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut first = String::new();
+    file.read_line(&mut first).unwrap();
+    assert_eq!("line X\n", first);
+    file.close().unwrap();
+}
+
+#[test]
+fn splits_large_reads_and_writes() {
+    let tmp_dir = tempdir::TempDir::new("sftp_limits").unwrap();
+    let mut server = TestSftpServer::new();
+    let mut client = server.client();
+    let limits = client.limits();
+    if client.has_extension("limits@openssh.com") {
+        assert!(limits.max_read_len > 0);
+    }
+    let path = tmp_dir.path().join("file");
+    let file = client.open_options().read(true).write(true).create(true).open(path.to_str().unwrap()).unwrap();
+    let data = vec![7; 4 * 1024 * 1024];
+    let written = file.write_at(&data, 0).unwrap();
+    assert!(written > 0 && written < data.len());
+    file.write_all_at(&data, 0).unwrap();
+    let mut buf = vec![0; data.len()];
+    let n = file.read_at(&mut buf, 0).unwrap();
+    assert!(n > 0 && n < data.len());
+    assert_eq!(data.len() as u64, std::fs::metadata(&path).unwrap().len());
+}
+
+#[test]
+fn rejects_oversized_input() {
+    let huge = vec![0x7f, 0xff, 0xff, 0xff, 2];
+    match sftp::Client::new(std::io::Cursor::new(huge), std::io::sink()) {
+        Err(sftp::Error::PacketTooLarge(0x7fffffff)) => {},
+        x => panic!("unexpected result: {:?}", x.err()),
+    }
+    let long_string = vec![0, 0, 0, 13, 2, 0, 0, 0, 3, 0x10, 0, 0, 0, b'a', b'b', b'c', b'd'];
+    match sftp::Client::new(std::io::Cursor::new(long_string), std::io::sink()) {
+        Err(sftp::Error::StringTooLong(0x10000000)) => {},
+        x => panic!("unexpected result: {:?}", x.err()),
+    }
+    // A version packet whose extension name claims to be longer than the packet itself.
+    let bad_string = vec![0, 0, 0, 13, 2, 0, 0, 0, 3, 0, 0, 0x10, 0, b'a', b'b', b'c', b'd'];
+    match sftp::Client::new(std::io::Cursor::new(bad_string), std::io::sink()) {
+        Err(sftp::Error::UnexpectedData) => {},
+        x => panic!("unexpected result: {:?}", x.err()),
+    }
+}
+
+#[test]
+fn protocol_frames_round_trip() {
+    use sftp::protocol::*;
+    use std::borrow::Cow;
+
+    let mut w = PacketWriter::new(Vec::new());
+    w.write_init(&FxpInit{version: 3, extensions: Vec::new()}).unwrap();
+    w.write_request(1, &FxpOpen{filename: b"/tmp/x".to_vec(), pflags: SSH_FXF_READ, attrs: FileAttr::new()}).unwrap();
+    w.write_request(2, &FxpWrite{handle: Cow::Borrowed(b"h"), offset: 7, data: Cow::Borrowed(b"data")}).unwrap();
+    w.write_request(3, &FxpPosixRename{oldpath: b"a".to_vec(), newpath: b"b".to_vec()}).unwrap();
+    let mut r = PacketReader::new(io::Cursor::new(w.into_inner()));
+    assert_eq!(r.read_request().unwrap(), SftpRequest{
+        req_id: 0, packet: SftpRequestPacket::Init(FxpInit{version: 3, extensions: Vec::new()})});
+    match r.read_request().unwrap() {
+        SftpRequest{req_id: 1, packet: SftpRequestPacket::Open(ref p)} => assert_eq!(p.filename, b"/tmp/x"),
+        x => panic!("unexpected request: {:?}", x),
+    }
+    match r.read_request().unwrap() {
+        SftpRequest{req_id: 2, packet: SftpRequestPacket::Write(ref p)} => assert_eq!(&p.data[..], b"data"),
+        x => panic!("unexpected request: {:?}", x),
+    }
+    match r.read_request().unwrap() {
+        SftpRequest{req_id: 3, packet: SftpRequestPacket::PosixRename(ref p)} => assert_eq!(p.newpath, b"b"),
+        x => panic!("unexpected request: {:?}", x),
+    }
+
+    let mut w = PacketWriter::new(Vec::new());
+    w.write_version(&FxpVersion{version: 3, extensions: Vec::new()}).unwrap();
+    w.write_response(1, &FxpHandle{handle: b"h".to_vec()}).unwrap();
+    w.write_response(2, &FxpStatus{code: FxpStatusCode::EOF, msg: "end".to_string()}).unwrap();
+    let mut r = PacketReader::new(io::Cursor::new(w.into_inner()));
+    assert_eq!(r.read_response().unwrap().packet,
+               SftpResponsePacket::Version(FxpVersion{version: 3, extensions: Vec::new()}));
+    assert_eq!(r.read_response().unwrap(), SftpResponse{
+        req_id: 1, packet: SftpResponsePacket::Handle(FxpHandle{handle: b"h".to_vec()})});
+    match r.read_response().unwrap().packet {
+        SftpResponsePacket::Status(ref s) => assert_eq!(s.code, FxpStatusCode::EOF),
+        x => panic!("unexpected response: {:?}", x),
+    }
+}
+
+#[derive(Clone)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Builds what a server sends in a session, in the JSON lines format `Replay` reads: its version,
+// then one frame per response.
+struct Recording {
+    server: sftp::protocol::PacketWriter<Vec<u8>>,
+    ends: Vec<usize>,
+}
+
+impl Recording {
+    fn new() -> Recording {
+        let mut server = sftp::protocol::PacketWriter::new(Vec::new());
+        server.write_version(&sftp::protocol::FxpVersion{version: 3, extensions: Vec::new()}).unwrap();
+        let ends = vec![server.get_ref().len()];
+        Recording{server: server, ends: ends}
+    }
+
+    fn respond<P>(&mut self, req_id: u32, packet: &P) -> &mut Recording
+        where P: sftp::protocol::Response + sftp::protocol::Sendable {
+        self.server.write_response(req_id, packet).unwrap();
+        self.ends.push(self.server.get_ref().len());
+        self
+    }
+
+    fn to_json_lines(&self) -> String {
+        let frames = self.server.get_ref();
+        let mut lines = String::new();
+        let mut start = 0;
+        for &end in self.ends.iter() {
+            let hex: Vec<String> = frames[start..end].iter().map(|b| format!("{:02x}", b)).collect();
+            lines.push_str(&format!("{{\"dir\":\"recv\",\"raw\":\"{}\"}}\n", hex.concat()));
+            start = end;
+        }
+        lines
+    }
+
+    fn replay(&self) -> sftp::Replay {
+        sftp::Replay::from_json_lines(io::Cursor::new(self.to_json_lines())).unwrap()
+    }
+}
+
+#[test]
+fn replays_recorded_session() {
+    use sftp::protocol::*;
+
+    // What a server would have sent: its version, then the attributes for one stat.
+    let mut attrs = FileAttr::new();
+    attrs.size = Some(5);
+    let (r, w) = Recording::new().respond(0, &attrs).replay().transport();
+    let out = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+    let tracer = sftp::Tracer::new(out.clone(), sftp::TraceFormat::JsonLines);
+    let mut client = sftp::Client::new(tracer.reader(r), tracer.writer(w)).unwrap();
+    assert_eq!(client.stat("/x").unwrap(), attrs);
+    drop(client);
+
+    // The trace of the replayed session can itself be replayed.
+    let trace = out.0.lock().unwrap().clone();
+    let trace = String::from_utf8(trace).unwrap();
+    assert_eq!(trace.lines().count(), 4);
+    assert!(trace.contains("FxpStat"));
+    let mut client = sftp::Replay::from_json_lines(io::Cursor::new(trace)).unwrap().client().unwrap();
+    assert_eq!(client.stat("/x").unwrap().size, Some(5));
+
+    match sftp::Replay::from_json_lines(io::Cursor::new("not json\n")) {
+        Err(sftp::Error::InvalidTrace(1)) => {},
+        x => panic!("unexpected result: {:?}", x.err()),
+    }
+}
+
+#[test]
+fn reads_data_into_caller_buffer() {
+    use sftp::protocol::*;
+
+    let mut client = Recording::new()
+        .respond(0, &FxpHandle{handle: b"h".to_vec()})
+        .respond(1, &FxpData{data: b"abcd".to_vec()})
+        // More than was asked for, which must not be written past the end of the buffer.
+        .respond(2, &FxpData{data: vec![7; 20]})
+        .replay().client().unwrap();
+    let file = client.open_options().read(true).open("/f").unwrap();
+    let mut buf = [0; 10];
+    assert_eq!(4, file.read_at(&mut buf, 0).unwrap());
+    assert_eq!(b"abcd", &buf[..4]);
+    assert!(file.read_at(&mut buf, 4).is_err());
+    assert_eq!([0; 6], buf[4..]);
+    assert_eq!(client.stats().bytes_read, 4);
+}
+
+#[test]
+fn reads_whole_files_as_the_data_arrives() {
+    use sftp::protocol::*;
+
+    let eof = FxpStatus{code: FxpStatusCode::EOF, msg: "end".to_string()};
+    let mut attrs = FileAttr::new();
+    // Not to be trusted for how much to allocate.
+    attrs.size = Some(u64::max_value());
+    let mut recording = Recording::new();
+    recording.respond(0, &FxpHandle{handle: b"h".to_vec()})
+        .respond(1, &attrs)
+        .respond(2, &FxpData{data: b"abc".to_vec()})
+        // The rest of the first read, then the other reads in flight.
+        .respond(18, &eof);
+    for req_id in 3..18 {
+        recording.respond(req_id, &eof);
+    }
+    let mut client = recording.replay().client().unwrap();
+    let events = Arc::new(Events(Mutex::new(Vec::new())));
+    client.set_progress(Some(events.clone()));
+    assert_eq!(b"abc".to_vec(), client.read("/f").unwrap());
+    assert_eq!(Some(&("finished", "/f".to_string(), 3)), events.0.lock().unwrap().last());
+
+    // Data after the end of the file is dropped, and not counted as transferred.
+    attrs.size = Some(0);
+    let mut recording = Recording::new();
+    recording.respond(0, &FxpHandle{handle: b"h".to_vec()})
+        .respond(1, &attrs)
+        .respond(2, &eof)
+        .respond(3, &FxpData{data: b"xyz".to_vec()})
+        .respond(18, &eof);
+    for req_id in 4..18 {
+        recording.respond(req_id, &eof);
+    }
+    let mut client = recording.replay().client().unwrap();
+    client.set_progress(Some(events.clone()));
+    assert_eq!(Vec::<u8>::new(), client.read("/f").unwrap());
+    assert_eq!(Some(&("finished", "/f".to_string(), 0)), events.0.lock().unwrap().last());
+
+    // More than was asked for.
+    let mut recording = Recording::new();
+    recording.respond(0, &FxpHandle{handle: b"h".to_vec()})
+        .respond(1, &attrs)
+        .respond(2, &FxpData{data: vec![1; 40000]});
+    for req_id in 3..18 {
+        recording.respond(req_id, &eof);
+    }
+    let mut client = recording.replay().client().unwrap();
+    match client.read("/f") {
+        Err(sftp::Error::UnexpectedData) => {},
+        x => panic!("unexpected result: {:?}", x),
+    }
+}
+
+#[test]
+fn copies_no_more_than_the_source_has() {
+    use sftp::protocol::*;
+
+    let mut attrs = FileAttr::new();
+    attrs.size = Some(10);
+    let mut client = Recording::new()
+        .respond(0, &FxpHandle{handle: b"src".to_vec()})
+        .respond(1, &attrs)
+        .respond(2, &FxpHandle{handle: b"dst".to_vec()})
+        .respond(3, &FxpData{data: vec![1; 10]})
+        .respond(4, &FxpStatus{code: FxpStatusCode::EOF, msg: "end".to_string()})
+        .respond(5, &FxpStatus{code: FxpStatusCode::Ok, msg: "ok".to_string()})
+        .replay().client().unwrap();
+    assert_eq!(10, client.copy_range("/src", 0, 100, "/dst", 0).unwrap());
+
+    // More than was asked for, which must not be written past the end of the range.
+    let mut client = Recording::new()
+        .respond(0, &FxpHandle{handle: b"src".to_vec()})
+        .respond(1, &attrs)
+        .respond(2, &FxpHandle{handle: b"dst".to_vec()})
+        .respond(3, &FxpData{data: vec![1; 101]})
+        .replay().client().unwrap();
+    match client.copy_range("/src", 0, 100, "/dst", 0) {
+        Err(sftp::Error::UnexpectedData) => {},
+        x => panic!("unexpected result: {:?}", x),
+    }
+    assert_eq!(client.stats().requests.get("write"), None);
+}
+
+#[test]
+fn verifies_written_contents() {
+    use sftp::protocol::*;
+
+    let ok = FxpStatus{code: FxpStatusCode::Ok, msg: "ok".to_string()};
+    // Without check-file or md5-hash, the file is read back to compute its checksum.
+    let mut client = Recording::new()
+        .respond(0, &FxpHandle{handle: b"w".to_vec()})
+        .respond(1, &ok)
+        .respond(2, &ok)
+        .respond(3, &FxpHandle{handle: b"r".to_vec()})
+        .respond(4, &FxpData{data: b"abd".to_vec()})
+        .respond(5, &FxpStatus{code: FxpStatusCode::EOF, msg: "end".to_string()})
+        .respond(6, &ok)
+        .replay().client().unwrap();
+    client.set_verify(Some(sftp::HashAlgorithm::Md5));
+    match client.write("/f", b"abc") {
+        Err(sftp::Error::ChecksumMismatch) => {},
+        x => panic!("unexpected result: {:?}", x),
+    }
+}
+
+#[test]
+fn file_io_keeps_error_details() {
+    use sftp::protocol::*;
+
+    // The connection ends right after the file is opened.
+    let mut client = Recording::new()
+        .respond(0, &FxpHandle{handle: b"h".to_vec()})
+        .replay().client().unwrap();
+    let mut file = client.open_options().read(true).write(true).open("/f").unwrap();
+    let e = file.read(&mut [0; 10]).unwrap_err();
+    assert!(e.to_string().starts_with("Receiver disconnected"), "{}", e);
+    let e = file.write(b"abc").unwrap_err();
+    assert!(e.to_string().starts_with("Receiver disconnected"), "{}", e);
+}
+
+#[test]
+fn collects_stats() {
+    use sftp::protocol::*;
+    use sftp::StatsSink;
+
+    let mut client = Recording::new()
+        .respond(0, &FileAttr::new())
+        .respond(1, &FxpStatus{code: FxpStatusCode::NoSuchFile, msg: "gone".to_string()})
+        .replay().client().unwrap();
+    client.stat("/a").unwrap();
+    assert!(client.stat("/b").is_err());
+    let stats = client.stats();
+    assert_eq!(stats.requests.get("init"), Some(&1));
+    assert_eq!(stats.latency.get("init").map(|h| h.count), Some(1));
+    assert_eq!(stats.requests.get("stat"), Some(&2));
+    assert_eq!(stats.outstanding, 0);
+    assert_eq!(stats.errors.get("no_such_file"), Some(&1));
+    assert_eq!(stats.latency.get("stat").map(|h| h.count), Some(2));
+
+    let mut sink = sftp::PrometheusSink::new(Vec::new());
+    sink.label("host", "example.com");
+    sink.export(&stats).unwrap();
+    let text = String::from_utf8(sink.into_inner()).unwrap();
+    assert!(text.contains("sftp_requests_total{host=\"example.com\",op=\"stat\"} 2\n"));
+    assert!(text.contains("sftp_status_errors_total{host=\"example.com\",code=\"no_such_file\"} 1\n"));
+    assert!(text.contains("sftp_request_duration_seconds_bucket{host=\"example.com\",op=\"stat\",le=\"+Inf\"} 2\n"));
+}
+
+// Lets writes through until `fail` is set.
+struct FailingWriter<W> {
+    inner: W,
+    fail: Arc<Mutex<bool>>,
+}
+
+impl<W> io::Write for FailingWriter<W> where W: io::Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if *self.fail.lock().unwrap() {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection gone"));
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn counts_only_requests_that_were_sent() {
+    let (r, w) = Recording::new().replay().transport();
+    let fail = Arc::new(Mutex::new(false));
+    let mut client = sftp::Client::new(r, FailingWriter{inner: w, fail: fail.clone()}).unwrap();
+    *fail.lock().unwrap() = true;
+    assert!(client.stat("/a").is_err());
+    let stats = client.stats();
+    assert_eq!(stats.requests.get("init"), Some(&1));
+    assert_eq!(stats.requests.get("stat"), None);
+    assert_eq!(stats.outstanding, 0);
+}